@@ -0,0 +1,265 @@
+use crate::color::Color;
+use crate::image::{Image, PixelBounds};
+use crate::integrator::{false_color, Features};
+
+// Arbitrary output variables, per pixel data about the first hit written
+// alongside the beauty pass for compositing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    Normal,
+    Depth,
+    Position,
+    Albedo,
+    ObjectId,
+    MaterialId,
+    Emission,
+    Direct,
+    Indirect,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 9] = [
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Emission,
+        Aov::Direct,
+        Aov::Indirect,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object-id",
+            Aov::MaterialId => "material-id",
+            Aov::Emission => "emission",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.into_iter().find(|aov| aov.name() == name)
+    }
+
+    // Channel names within the layer of a multi-layer file, one for each
+    // component of the image that is used.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::Albedo | Aov::Emission | Aov::Direct | Aov::Indirect => &["R", "G", "B"],
+        }
+    }
+}
+
+// Per pixel first hit features. Everything continuous is averaged over the
+// samples of a pixel with a box filter, so it lines up with pixels exactly.
+// IDs can't be averaged, they come from the first sample of each pixel and
+// are one based, zero is the sky. Material IDs are handed out in the order
+// materials are first seen, which is stable for a given scene and settings.
+pub struct FeatureBuffers {
+    pub albedo: Image,
+    pub normal: Image,
+    pub depth: Image,
+    pub position: Image,
+    pub emission: Image,
+    pub direct: Image,
+    pub indirect: Image,
+    pub object_ids: Vec<u32>,
+    pub material_ids: Vec<u32>,
+    materials: Vec<usize>,
+}
+
+impl FeatureBuffers {
+    pub fn new(width: usize, height: usize) -> FeatureBuffers {
+        FeatureBuffers {
+            albedo: Image::new(width, height),
+            normal: Image::new(width, height),
+            depth: Image::new(width, height),
+            position: Image::new(width, height),
+            emission: Image::new(width, height),
+            direct: Image::new(width, height),
+            indirect: Image::new(width, height),
+            object_ids: vec![0; width * height],
+            material_ids: vec![0; width * height],
+            materials: vec![],
+        }
+    }
+
+    pub fn add(&mut self, x: usize, y: usize, features: &Features) {
+        let index = self.albedo.index(x, y);
+        if self.albedo.samples[index] == 0 {
+            self.object_ids[index] = features.object_id.map_or(0, |id| id + 1);
+            self.material_ids[index] = match features.material {
+                Some(key) => self.material_id(key),
+                None => 0,
+            };
+        }
+
+        self.albedo.add_sample(x, y, features.albedo);
+        self.normal.add_sample(x, y, features.normal);
+        self.depth.add_sample(x, y, gray(features.depth));
+        self.position.add_sample(x, y, features.position);
+        self.emission.add_sample(x, y, features.emission);
+        self.direct.add_sample(x, y, features.direct);
+        self.indirect.add_sample(x, y, features.indirect);
+    }
+
+    // Features of the pixels within `bounds`
+    pub fn crop(&self, bounds: &PixelBounds) -> FeatureBuffers {
+        let crop_ids = |ids: &[u32]| {
+            (bounds.y0..bounds.y1)
+                .flat_map(|y| (bounds.x0..bounds.x1).map(move |x| ids[self.albedo.index(x, y)]))
+                .collect()
+        };
+        FeatureBuffers {
+            albedo: self.albedo.crop(bounds),
+            normal: self.normal.crop(bounds),
+            depth: self.depth.crop(bounds),
+            position: self.position.crop(bounds),
+            emission: self.emission.crop(bounds),
+            direct: self.direct.crop(bounds),
+            indirect: self.indirect.crop(bounds),
+            object_ids: crop_ids(&self.object_ids),
+            material_ids: crop_ids(&self.material_ids),
+            materials: self.materials.clone(),
+        }
+    }
+
+    // Adds the features accumulated in `other`. IDs stay those of this one,
+    // which had the first samples.
+    pub fn merge(&mut self, other: &FeatureBuffers) {
+        self.albedo.merge(&other.albedo);
+        self.normal.merge(&other.normal);
+        self.depth.merge(&other.depth);
+        self.position.merge(&other.position);
+        self.emission.merge(&other.emission);
+        self.direct.merge(&other.direct);
+        self.indirect.merge(&other.indirect);
+    }
+
+    fn material_id(&mut self, key: usize) -> u32 {
+        let position = match self.materials.iter().position(|&material| material == key) {
+            Some(position) => position,
+            None => {
+                self.materials.push(key);
+                self.materials.len() - 1
+            }
+        };
+        position as u32 + 1
+    }
+
+    // The raw values of an AOV, single valued ones repeated in every
+    // component
+    pub fn layer(&self, aov: Aov) -> Image {
+        match aov {
+            Aov::Normal => resolve(&self.normal),
+            Aov::Depth => resolve(&self.depth),
+            Aov::Position => resolve(&self.position),
+            Aov::Albedo => resolve(&self.albedo),
+            Aov::Emission => resolve(&self.emission),
+            Aov::Direct => resolve(&self.direct),
+            Aov::Indirect => resolve(&self.indirect),
+            Aov::ObjectId => self.map_ids(&self.object_ids, |id| gray(id as f32)),
+            Aov::MaterialId => self.map_ids(&self.material_ids, |id| gray(id as f32)),
+        }
+    }
+
+    // An AOV made viewable as an 8 bit image: normals remapped to [0, 1],
+    // depth scaled by the farthest hit and IDs as false colors.
+    pub fn preview(&self, aov: Aov) -> Image {
+        let id_color = |id: u32| {
+            if id == 0 {
+                Color::zero()
+            } else {
+                false_color(id as u64)
+            }
+        };
+        match aov {
+            Aov::Normal => {
+                let mut image = self.layer(aov);
+                for pixel in image.pixels.iter_mut() {
+                    if !pixel.near_zero() {
+                        *pixel = 0.5 * (*pixel + 1.0);
+                    }
+                }
+                image
+            }
+            Aov::Depth => {
+                let mut image = self.layer(aov);
+                let farthest = image
+                    .pixels
+                    .iter()
+                    .fold(0.0_f32, |farthest, pixel| farthest.max(pixel.x));
+                if farthest > 0.0 {
+                    for pixel in image.pixels.iter_mut() {
+                        *pixel = *pixel / farthest;
+                    }
+                }
+                image
+            }
+            Aov::ObjectId => self.map_ids(&self.object_ids, id_color),
+            Aov::MaterialId => self.map_ids(&self.material_ids, id_color),
+            _ => self.layer(aov),
+        }
+    }
+
+    fn map_ids(&self, ids: &[u32], color: impl Fn(u32) -> Color) -> Image {
+        let mut image = Image::new(self.albedo.width, self.albedo.height);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                image.add_sample(x, y, color(ids[image.index(x, y)]));
+            }
+        }
+        image
+    }
+}
+
+// Averages of an accumulated buffer, one sample each
+fn resolve(buffer: &Image) -> Image {
+    let mut image = Image::new(buffer.width, buffer.height);
+    for y in 0..buffer.height {
+        for x in 0..buffer.width {
+            image.add_sample(x, y, buffer.get(x, y));
+        }
+    }
+    image
+}
+
+fn gray(value: f32) -> Color {
+    Color {
+        x: value,
+        y: value,
+        z: value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aov::*;
+
+    #[test]
+    fn test_ids_come_from_the_first_sample() {
+        let mut buffers = FeatureBuffers::new(2, 1);
+        let hit = |object_id: u32, material: usize| Features {
+            object_id: Some(object_id),
+            material: Some(material),
+            ..Features::miss()
+        };
+        buffers.add(0, 0, &hit(3, 0xbeef));
+        buffers.add(0, 0, &hit(5, 0xcafe));
+        buffers.add(1, 0, &hit(5, 0xcafe));
+
+        assert_eq!(buffers.object_ids, vec![4, 6]);
+        assert_eq!(buffers.material_ids, vec![1, 2]);
+        assert_eq!(buffers.layer(Aov::ObjectId).get(1, 0).x, 6.0);
+    }
+}
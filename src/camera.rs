@@ -1,11 +1,278 @@
+use std::f32::consts::PI;
+
+use crate::lens::LensSystem;
 use crate::ray::*;
+use crate::sampler::*;
+use crate::spectrum::sample_wavelength;
 use crate::vector::*;
 
+// Lens prescriptions are in millimeters, scenes in meters
+const MILLIMETERS_PER_UNIT: f32 = 1000.0;
+
+// Exposure controls of a real camera. Together they decide how much of the
+// scene's light ends up on the sensor, so lights can be given in real units
+// with radiance measured in cd/m^2 and scene units in meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhysicalCamera {
+    pub iso: f32,
+    // Seconds the shutter stays open, which is also how long moving objects
+    // get to blur
+    pub shutter_speed: f32,
+    pub f_number: f32,
+    // Width of the sensor in meters, 36mm for full frame. Along with the
+    // field of view it gives the focal length, and so the aperture size.
+    pub sensor_width: f32,
+    pub focus_distance: f32,
+}
+
+impl Default for PhysicalCamera {
+    fn default() -> Self {
+        // "Sunny 16" settings
+        PhysicalCamera {
+            iso: 100.0,
+            shutter_speed: 1.0 / 125.0,
+            f_number: 16.0,
+            sensor_width: 0.036,
+            focus_distance: 1.0,
+        }
+    }
+}
+
+impl PhysicalCamera {
+    // Exposure value at ISO 100
+    pub fn ev100(&self) -> f32 {
+        (self.f_number * self.f_number / self.shutter_speed * 100.0 / self.iso).log2()
+    }
+
+    // Scale from scene luminance to film values, using the saturation based
+    // sensitivity of ISO 12232 so that the brightest luminance the sensor
+    // can record maps to one.
+    // https://seblagarde.files.wordpress.com/2015/07/course_notes_moving_frostbite_to_pbr_v32.pdf
+    pub fn exposure(&self) -> f32 {
+        1.0 / (1.2 * self.ev100().exp2())
+    }
+
+    // Radius of the entrance pupil of a lens with `focal_length`
+    pub fn aperture_radius(&self, focal_length: f32) -> f32 {
+        0.5 * focal_length / self.f_number
+    }
+}
+
+// How the camera maps points on the film to directions. Only the
+// perspective projection models a lens, the others are pinholes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Perspective,
+    // Parallel rays covering the same area as the perspective view does at
+    // the focus distance, for technical views
+    Orthographic,
+    // Fisheyes fit a circle of `fov` degrees into the height of the image,
+    // the corners outside of it stay black. Equidistant spaces angles evenly
+    // across the image, equisolid keeps areas proportional to solid angle
+    // like most real fisheye lenses.
+    FisheyeEquidistant { fov: f32 },
+    FisheyeEquisolid { fov: f32 },
+    // Full 360 by 180 degree panorama, best rendered at 2:1, looking down
+    // the middle of the image
+    Equirectangular,
+    // All six faces of a cube side by side, in the +X, -X, +Y, -Y, +Z, -Z
+    // order and orientation of OpenGL cube maps, relative to the camera.
+    // Render at 6:1.
+    CubeMap,
+}
+
+impl Projection {
+    pub fn from_name(name: &str, fov: f32) -> Option<Projection> {
+        match name {
+            "perspective" => Some(Projection::Perspective),
+            "orthographic" => Some(Projection::Orthographic),
+            "fisheye-equidistant" => Some(Projection::FisheyeEquidistant { fov }),
+            "fisheye-equisolid" => Some(Projection::FisheyeEquisolid { fov }),
+            "equirectangular" => Some(Projection::Equirectangular),
+            "cubemap" => Some(Projection::CubeMap),
+            _ => None,
+        }
+    }
+}
+
+// Direction in the camera's frame, x right, y up and looking down -z,
+// given spherical angles from the view axis
+fn from_view_axis(theta: f32, phi: f32) -> Vec3 {
+    Vec3 {
+        x: theta.sin() * phi.cos(),
+        y: theta.sin() * phi.sin(),
+        z: -theta.cos(),
+    }
+}
+
+// Direction through (s, t) of a 6:1 strip of cube faces, in the camera frame
+fn cube_map_direction(s: f32, t: f32) -> Vec3 {
+    let face = ((s * 6.0) as usize).min(5);
+    // Face coordinates in [-1, 1], going right and down
+    let sc = 2.0 * (s * 6.0 - face as f32) - 1.0;
+    let tc = 1.0 - 2.0 * t;
+    let (x, y, z) = match face {
+        0 => (1.0, -tc, -sc),
+        1 => (-1.0, -tc, sc),
+        2 => (sc, 1.0, tc),
+        3 => (sc, -1.0, -tc),
+        4 => (sc, -tc, 1.0),
+        _ => (-sc, -tc, -1.0),
+    };
+    Vec3 { x, y, z }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    // Left eye in the left half
+    SideBySide,
+    // Left eye in the top half, the usual layout for 360 video
+    TopBottom,
+}
+
+// Two eyes `interocular` apart, sharing one image. Perspective eyes look
+// parallel with their films shifted so that objects at `convergence` line
+// up in both, which keeps the eyes from diverging on distant objects. The
+// equirectangular projection turns into omnidirectional stereo, where eyes
+// sit on a circle and every column looks out from the eye tangent to it.
+// https://developers.google.com/vr/jump/rendering-ods-content.pdf
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stereo {
+    pub interocular: f32,
+    pub convergence: f32,
+    pub layout: StereoLayout,
+}
+
+impl Stereo {
+    // Film position within the eye's half of the image, and the offset of
+    // the eye along the camera's x axis
+    fn eye(&self, s: f32, t: f32) -> (f32, f32, f32) {
+        let (s, t, left) = match self.layout {
+            StereoLayout::SideBySide => ((2.0 * s).fract(), t, s < 0.5),
+            StereoLayout::TopBottom => (s, (2.0 * t).fract(), t >= 0.5),
+        };
+        let offset = if left { -0.5 } else { 0.5 } * self.interocular;
+        (s, t, offset)
+    }
+}
+
+// Brown-Conrady lens distortion with OpenCV's coefficients, taking
+// undistorted normalized image coordinates (x, y) to distorted ones
+// https://docs.opencv.org/4.x/d9/d0c/group__calib3d.html
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Distortion {
+    // Radial
+    pub k1: f32,
+    pub k2: f32,
+    pub k3: f32,
+    // Tangential
+    pub p1: f32,
+    pub p2: f32,
+}
+
+impl Distortion {
+    // In OpenCV's order, k1, k2, p1, p2 and optionally k3
+    pub fn from_coefficients(coefficients: &[f32]) -> Option<Distortion> {
+        match *coefficients {
+            [k1, k2, p1, p2] => Some(Distortion {
+                k1,
+                k2,
+                k3: 0.0,
+                p1,
+                p2,
+            }),
+            [k1, k2, p1, p2, k3] => Some(Distortion { k1, k2, k3, p1, p2 }),
+            _ => None,
+        }
+    }
+
+    pub fn distort(&self, x: f32, y: f32) -> (f32, f32) {
+        let r2 = x * x + y * y;
+        let radial = 1.0 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+        let dx = 2.0 * self.p1 * x * y + self.p2 * (r2 + 2.0 * x * x);
+        let dy = self.p1 * (r2 + 2.0 * y * y) + 2.0 * self.p2 * x * y;
+        (x * radial + dx, y * radial + dy)
+    }
+
+    // Fixed point iteration like OpenCV's undistortPoints, which converges
+    // for the moderate distortion of real lenses
+    pub fn undistort(&self, xd: f32, yd: f32) -> (f32, f32) {
+        let (mut x, mut y) = (xd, yd);
+        for _ in 0..20 {
+            let r2 = x * x + y * y;
+            let radial = 1.0 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+            let dx = 2.0 * self.p1 * x * y + self.p2 * (r2 + 2.0 * x * x);
+            let dy = self.p1 * (r2 + 2.0 * y * y) + 2.0 * self.p2 * x * y;
+            x = (xd - dx) / radial;
+            y = (yd - dy) / radial;
+        }
+        (x, y)
+    }
+}
+
+// Pinhole intrinsics of a calibrated camera in pixels, following OpenCV:
+// pixel centers sit at integer coordinates and y goes down the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub fx: f32,
+    pub fy: f32,
+    pub cx: f32,
+    pub cy: f32,
+    // Pixels x moves per unit of y, zero for any modern sensor
+    pub skew: f32,
+    pub width: usize,
+    pub height: usize,
+    pub distortion: Option<Distortion>,
+}
+
+impl Calibration {
+    // Point on the image plane at a distance of one through film position
+    // (s, t), in the camera's frame
+    fn image_plane(&self, s: f32, t: f32) -> Vec3 {
+        let px = s * self.width as f32 - 0.5;
+        let py = (1.0 - t) * self.height as f32 - 0.5;
+        let yd = (py - self.cy) / self.fy;
+        let xd = (px - self.cx - self.skew * yd) / self.fx;
+        let (x, y) = match self.distortion {
+            Some(distortion) => distortion.undistort(xd, yd),
+            None => (xd, yd),
+        };
+        Vec3 { x, y: -y, z: -1.0 }
+    }
+}
+
+// Camera looking down -w through one of the projections above. With the
+// perspective projection points at `focus_distance` are in focus and
+// everything else is blurred by the size of the lens, which is either a
+// thin lens or a traced lens system. Rays are spread over the time the
+// shutter is open for motion blur.
 pub struct Camera {
     origin: Vec3,
-    horizontal: Vec3,
-    vertical: Vec3,
-    lower_left_corner: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    // Size of the image plane at a distance of one
+    viewport_width: f32,
+    viewport_height: f32,
+    // Replaces the viewport for the perspective projection
+    pub calibration: Option<Calibration>,
+    pub projection: Projection,
+    pub stereo: Option<Stereo>,
+    pub lens_radius: f32,
+    // Replaces the thin lens, its focal length and film size decide the
+    // field of view
+    pub lens: Option<LensSystem>,
+    // Scales ray weights so the middle of the film sees the scene at full
+    // brightness through the lens
+    lens_normalization: f32,
+    // Pick a wavelength and trace the lens at it, for chromatic aberration.
+    // The spectral path tracer carries on with the ray's wavelength.
+    pub spectral: bool,
+    pub focus_distance: f32,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    // Multiplier from scene radiance to film values
+    pub exposure: f32,
 }
 
 impl Camera {
@@ -13,41 +280,508 @@ impl Camera {
         let aspect_ratio = 16.0 / 9.0;
         let viewport_height = 2.0;
         let viewport_width = aspect_ratio * viewport_height;
-        let focal_length = 1.0;
 
-        let origin = Vec3::zero();
-        let horizontal = Vec3 {
-            x: viewport_width,
+        Camera {
+            origin: Vec3::zero(),
+            u: Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            v: Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            w: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            viewport_width,
+            viewport_height,
+            calibration: None,
+            projection: Projection::Perspective,
+            stereo: None,
+            lens_radius: 0.0,
+            lens: None,
+            lens_normalization: 1.0,
+            spectral: false,
+            focus_distance: 1.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            exposure: 1.0,
+        }
+    }
+
+    // Camera at `look_from` pointed at `look_at`, with a vertical field of
+    // view in degrees
+    pub fn look_at(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        vertical_fov: f32,
+        aspect_ratio: f32,
+    ) -> Camera {
+        let viewport_height = 2.0 * (vertical_fov.to_radians() / 2.0).tan();
+        let w = unit_vector(&(look_from - look_at));
+        let u = unit_vector(&cross(&vup, &w));
+        let v = cross(&w, &u);
+
+        Camera {
+            origin: look_from,
+            u,
+            v,
+            w,
+            viewport_width: aspect_ratio * viewport_height,
+            viewport_height,
+            ..Camera::new()
+        }
+    }
+
+    // Camera matching a calibrated real one, from its 3x3 intrinsic matrix,
+    // a 4x4 camera to world matrix and the image size it was calibrated
+    // at, which may differ from the size rendered at. Both matrices use
+    // OpenCV's camera frame, looking down +z with y going down the image,
+    // and the intrinsics may have a skew term.
+    pub fn from_calibration(
+        intrinsics: &[[f32; 3]; 3],
+        extrinsic: &[[f32; 4]; 4],
+        width: usize,
+        height: usize,
+    ) -> Camera {
+        let column = |j: usize| Vec3 {
+            x: extrinsic[0][j],
+            y: extrinsic[1][j],
+            z: extrinsic[2][j],
+        };
+        let calibration = Calibration {
+            fx: intrinsics[0][0],
+            fy: intrinsics[1][1],
+            cx: intrinsics[0][2],
+            cy: intrinsics[1][2],
+            skew: intrinsics[0][1],
+            width,
+            height,
+            distortion: None,
+        };
+
+        Camera {
+            origin: column(3),
+            u: column(0),
+            v: -column(1),
+            w: -column(2),
+            viewport_width: width as f32 / calibration.fx,
+            viewport_height: height as f32 / calibration.fy,
+            calibration: Some(calibration),
+            ..Camera::new()
+        }
+    }
+
+    pub fn with_distortion(mut self, distortion: Distortion) -> Camera {
+        if let Some(calibration) = self.calibration.as_mut() {
+            calibration.distortion = Some(distortion);
+        }
+        self
+    }
+
+    // Take aperture, shutter and exposure from physical settings. The focal
+    // length follows from the sensor filling the field of view.
+    pub fn with_physical(self, settings: &PhysicalCamera) -> Camera {
+        let focal_length = settings.sensor_width / self.viewport_width;
+        Camera {
+            lens_radius: settings.aperture_radius(focal_length),
+            focus_distance: settings.focus_distance,
+            shutter_open: 0.0,
+            shutter_close: settings.shutter_speed,
+            exposure: settings.exposure(),
+            ..self
+        }
+    }
+
+    pub fn with_projection(self, projection: Projection) -> Camera {
+        Camera { projection, ..self }
+    }
+
+    pub fn with_stereo(self, stereo: Stereo) -> Camera {
+        Camera {
+            stereo: Some(stereo),
+            ..self
+        }
+    }
+
+    // Traces rays through `lens`, focused at the camera's focus distance,
+    // which fails when that is too close for the lens
+    pub fn with_lens(self, mut lens: LensSystem) -> Result<Camera, String> {
+        lens.focus(self.focus_distance * MILLIMETERS_PER_UNIT)?;
+
+        // Average weight over the rear element seen from the film center
+        let n = 32;
+        let mut total = 0.0;
+        for i in 0..n * n {
+            let u = ((i % n) as f32 + 0.5) / n as f32;
+            let v = ((i / n) as f32 + 0.5) / n as f32;
+            if let Some(weight) =
+                lens_ray(&lens, Vec3::zero(), (u, v), None).map(|(_, weight)| weight)
+            {
+                total += weight;
+            }
+        }
+        let average = total / (n * n) as f32;
+
+        Ok(Camera {
+            lens: Some(lens),
+            lens_normalization: if average > 0.0 { 1.0 / average } else { 1.0 },
+            ..self
+        })
+    }
+
+    fn to_world(&self, a: &Vec3) -> Vec3 {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+
+    // Ray through (s, t) on the film, (0, 0) being the lower left corner.
+    // Takes the lens position and time from the sampler. Fisheyes have no
+    // ray outside of their image circle, and lens systems none where the
+    // elements block it. A stereo camera splits the film between the eyes.
+    // Returns the ray and the weight of what it sees, which is only below
+    // one when a lens system darkens the edges of the image.
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        let (s, t, eye) = match self.stereo {
+            Some(stereo) => stereo.eye(s, t),
+            None => (s, t, 0.0),
+        };
+        let eye_offset = Vec3 {
+            x: eye,
             y: 0.0,
             z: 0.0,
         };
-        let vertical = Vec3 {
+
+        sampler.set_dimension(LENS_DIMENSION);
+        let lens_sample = sampler.get_2d();
+        let lens = self.lens_radius * sample_unit_disk(lens_sample);
+
+        sampler.set_dimension(TIME_DIMENSION);
+        let time = self.shutter_open + sampler.get_1d() * (self.shutter_close - self.shutter_open);
+
+        // Position on the film relative to its center, in units of its height
+        let aspect_ratio = self.viewport_width / self.viewport_height;
+        let x = (s - 0.5) * aspect_ratio;
+        let y = t - 0.5;
+
+        let wavelength = if self.spectral {
+            sampler.set_dimension(WAVELENGTH_DIMENSION);
+            Some(sample_wavelength(sampler.get_1d()))
+        } else {
+            None
+        };
+
+        let mut weight = 1.0;
+        let (origin, direction) = match (self.projection, &self.lens) {
+            (Projection::Perspective, Some(lens_system)) => {
+                // The lens flips the image, so the film is read upside down
+                let film_height = lens_system.film_width / aspect_ratio;
+                let film = Vec3 {
+                    x: -(s - 0.5) * lens_system.film_width,
+                    y: -(t - 0.5) * film_height,
+                    z: 0.0,
+                };
+                let (ray, lens_weight) = lens_ray(lens_system, film, lens_sample, wavelength)?;
+                weight = lens_weight * self.lens_normalization;
+                (
+                    eye_offset + ray.origin / MILLIMETERS_PER_UNIT,
+                    ray.direction,
+                )
+            }
+            (Projection::Perspective, None) => {
+                // Through the point the center of the eyes sees at the
+                // convergence distance, then on to the focus distance
+                let convergence = self.stereo.map_or(1.0, |stereo| stereo.convergence);
+                let image_plane = match &self.calibration {
+                    Some(calibration) => calibration.image_plane(s, t),
+                    None => Vec3 {
+                        x: (s - 0.5) * self.viewport_width,
+                        y: (t - 0.5) * self.viewport_height,
+                        z: -1.0,
+                    },
+                };
+                let converged = convergence * image_plane;
+                let target =
+                    eye_offset + (self.focus_distance / convergence) * (converged - eye_offset);
+                let origin = eye_offset + lens;
+                (origin, target - origin)
+            }
+            (Projection::Orthographic, _) => {
+                let origin = self.focus_distance
+                    * Vec3 {
+                        x: (s - 0.5) * self.viewport_width,
+                        y: (t - 0.5) * self.viewport_height,
+                        z: 0.0,
+                    };
+                let direction = Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -1.0,
+                };
+                (eye_offset + origin, direction)
+            }
+            (Projection::FisheyeEquidistant { fov } | Projection::FisheyeEquisolid { fov }, _) => {
+                // Distance from the center, one at the edge of the circle
+                let r = 2.0 * (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                let half_fov = (fov / 2.0).to_radians();
+                let theta = match self.projection {
+                    Projection::FisheyeEquidistant { .. } => r * half_fov,
+                    _ => 2.0 * (r * (half_fov / 2.0).sin()).asin(),
+                };
+                (eye_offset, from_view_axis(theta, y.atan2(x)))
+            }
+            (Projection::Equirectangular, _) => {
+                let longitude = (s - 0.5) * 2.0 * PI;
+                let latitude = (t - 0.5) * PI;
+                let direction = Vec3 {
+                    x: latitude.cos() * longitude.sin(),
+                    y: latitude.sin(),
+                    z: -latitude.cos() * longitude.cos(),
+                };
+                // The eye is to the side of the horizontal view direction
+                let side = Vec3 {
+                    x: longitude.cos(),
+                    y: 0.0,
+                    z: longitude.sin(),
+                };
+                (eye * side, direction)
+            }
+            (Projection::CubeMap, _) => (eye_offset, cube_map_direction(s, t)),
+        };
+
+        let ray = Ray {
+            origin: self.origin + self.to_world(&origin),
+            direction: self.to_world(&direction),
+            wavelength,
+            time,
+        };
+        Some((ray, weight))
+    }
+}
+
+// Ray from `film` through a point on the rear element picked by `u`, out
+// into the scene in lens space. Its weight is the cos^4 falloff of the
+// irradiance on the film.
+fn lens_ray(
+    lens: &LensSystem,
+    film: Vec3,
+    u: (f32, f32),
+    wavelength: Option<f32>,
+) -> Option<(Ray, f32)> {
+    let rear = lens.rear_radius() * sample_unit_disk(u);
+    let target = Vec3 {
+        x: rear.x,
+        y: rear.y,
+        z: lens.rear_z(),
+    };
+    let ray = Ray {
+        origin: film,
+        direction: target - film,
+        wavelength,
+        time: 0.0,
+    };
+    let cos_theta = unit_vector(&ray.direction).z.abs();
+    let out = lens.trace_from_film(&ray, wavelength)?;
+    Some((out, cos_theta.powi(4)))
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::*;
+
+    #[test]
+    fn test_exposure() {
+        // Sunny 16 is EV 15, and f/1 for 1.2 seconds at ISO 100 records one
+        // cd/m^2 as one
+        let sunny = PhysicalCamera::default();
+        assert!((sunny.ev100() - 15.0).abs() < 0.05);
+        let dim = PhysicalCamera {
+            f_number: 1.0,
+            shutter_speed: 1.2,
+            ..Default::default()
+        };
+        assert!((dim.exposure() - 1.0).abs() < 1e-5);
+        // Doubling ISO is worth a stop
+        let fast = PhysicalCamera { iso: 200.0, ..dim };
+        assert!((fast.exposure() - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_projections() {
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+        let forward = Vec3 {
             x: 0.0,
-            y: viewport_height,
-            z: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        let direction = |projection: Projection, s: f32, t: f32, sampler: &mut dyn Sampler| {
+            let camera = Camera::new().with_projection(projection);
+            unit_vector(&camera.get_ray(s, t, sampler).unwrap().0.direction)
         };
-        let lower_left_corner = origin
-            - horizontal / 2.0
-            - vertical / 2.0
-            - Vec3 {
+
+        // Everything looks ahead from the center of the image
+        for projection in [
+            Projection::Perspective,
+            Projection::Orthographic,
+            Projection::FisheyeEquidistant { fov: 180.0 },
+            Projection::FisheyeEquisolid { fov: 180.0 },
+            Projection::Equirectangular,
+        ] {
+            assert!((direction(projection, 0.5, 0.5, sampler.as_mut()) - forward).length() < 1e-5);
+        }
+
+        // The top of a 180 degree fisheye looks straight up, and the corners
+        // are outside the image circle
+        let up = direction(
+            Projection::FisheyeEquisolid { fov: 180.0 },
+            0.5,
+            1.0,
+            sampler.as_mut(),
+        );
+        assert!(
+            (up - Vec3 {
                 x: 0.0,
+                y: 1.0,
+                z: 0.0
+            })
+            .length()
+                < 1e-5
+        );
+        let camera = Camera::new().with_projection(Projection::FisheyeEquidistant { fov: 180.0 });
+        assert!(camera.get_ray(0.0, 0.0, sampler.as_mut()).is_none());
+
+        // Equirectangular wraps around behind the camera at the edges
+        let behind = direction(Projection::Equirectangular, 0.0, 0.5, sampler.as_mut());
+        assert!((behind + forward).length() < 1e-5);
+
+        // Cube face centers look down the axes, -Z being straight ahead
+        let centers = [
+            (1.0, 0.0, 0.0),
+            (-1.0, 0.0, 0.0),
+            (0.0, 1.0, 0.0),
+            (0.0, -1.0, 0.0),
+            (0.0, 0.0, 1.0),
+            (0.0, 0.0, -1.0),
+        ];
+        for (face, (x, y, z)) in centers.into_iter().enumerate() {
+            let s = (face as f32 + 0.5) / 6.0;
+            assert!(
+                (direction(Projection::CubeMap, s, 0.5, sampler.as_mut()) - Vec3 { x, y, z })
+                    .length()
+                    < 1e-5
+            );
+        }
+    }
+
+    #[test]
+    fn test_calibration_round_trip() {
+        // Rotated a quarter turn about y and moved, with some distortion
+        let intrinsics = [[800.0, 1.5, 330.0], [0.0, 780.0, 235.0], [0.0, 0.0, 1.0]];
+        let extrinsic = [
+            [0.0, 0.0, 1.0, 2.0],
+            [0.0, 1.0, 0.0, 1.0],
+            [-1.0, 0.0, 0.0, -3.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        let distortion = Distortion {
+            k1: -0.2,
+            k2: 0.05,
+            k3: 0.0,
+            p1: 0.001,
+            p2: -0.002,
+        };
+        let camera =
+            Camera::from_calibration(&intrinsics, &extrinsic, 640, 480).with_distortion(distortion);
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+
+        // Project world points the way OpenCV does, then check the ray
+        // through the resulting pixel goes back through them
+        for point in [
+            Vec3 {
+                x: 7.0,
+                y: 1.5,
+                z: -3.5,
+            },
+            Vec3 {
+                x: 5.0,
                 y: 0.0,
-                z: focal_length,
-            };
+                z: -1.8,
+            },
+            Vec3 {
+                x: 9.0,
+                y: 2.0,
+                z: -4.0,
+            },
+        ] {
+            let relative = point
+                - Vec3 {
+                    x: 2.0,
+                    y: 1.0,
+                    z: -3.0,
+                };
+            // World to camera is the transposed rotation
+            let camera_point = [0, 1, 2].map(|j| {
+                (0..3)
+                    .map(|i| extrinsic[i][j] * [relative.x, relative.y, relative.z][i])
+                    .sum::<f32>()
+            });
+            let (xd, yd) = distortion.distort(
+                camera_point[0] / camera_point[2],
+                camera_point[1] / camera_point[2],
+            );
+            let px = 800.0 * xd + 1.5 * yd + 330.0;
+            let py = 780.0 * yd + 235.0;
 
-        Camera {
-            origin,
-            horizontal,
-            vertical,
-            lower_left_corner,
+            let s = (px + 0.5) / 640.0;
+            let t = 1.0 - (py + 0.5) / 480.0;
+            let (ray, _) = camera.get_ray(s, t, sampler.as_mut()).unwrap();
+            let to_point = unit_vector(&(point - ray.origin));
+            assert!((unit_vector(&ray.direction) - to_point).length() < 1e-4);
         }
     }
 
-    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
-        Ray {
-            origin: self.origin,
-            direction: self.lower_left_corner + u * self.horizontal + v * self.vertical
-                - self.origin,
+    #[test]
+    fn test_stereo() {
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+        let stereo = Stereo {
+            interocular: 0.064,
+            convergence: 3.0,
+            layout: StereoLayout::SideBySide,
+        };
+        let camera = Camera::new().with_stereo(stereo);
+
+        // The same film position in both eyes meets at the convergence plane
+        let left = camera.get_ray(0.1, 0.7, sampler.as_mut()).unwrap().0;
+        let right = camera.get_ray(0.6, 0.7, sampler.as_mut()).unwrap().0;
+        assert!((left.origin.x + 0.032).abs() < 1e-6 && (right.origin.x - 0.032).abs() < 1e-6);
+        let at_convergence = |ray: &Ray| ray.at(-3.0 / ray.direction.z);
+        assert!((at_convergence(&left) - at_convergence(&right)).length() < 1e-5);
+
+        // ODS eyes sit on a circle, at right angles to where they look
+        let ods = Camera::new()
+            .with_projection(Projection::Equirectangular)
+            .with_stereo(Stereo {
+                layout: StereoLayout::TopBottom,
+                ..stereo
+            });
+        for (s, t) in [(0.1, 0.8), (0.4, 0.6), (0.7, 0.3), (0.9, 0.1)] {
+            let (ray, _) = ods.get_ray(s, t, sampler.as_mut()).unwrap();
+            assert!((ray.origin.length() - 0.032).abs() < 1e-6);
+            assert!(dot(&ray.origin, &ray.direction).abs() < 1e-6);
         }
     }
 }
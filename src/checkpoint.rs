@@ -0,0 +1,400 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+use crate::aov::FeatureBuffers;
+use crate::color::Color;
+use crate::image::Image;
+use crate::render::AdaptiveSampling;
+use crate::sampler::SamplerKind;
+
+// Everything a progressive render has accumulated after some passes. The
+// samplers are stateless, each sample is a hash of the seed, pixel, sample
+// index and dimension, so the seed, the sampler and how many samples each
+// pixel has taken are all the random state there is. Continuing from a
+// checkpoint gives the same image as never having stopped.
+pub struct Checkpoint {
+    // Identifies the scene and camera, renders of different ones can't be
+    // combined
+    pub scene_hash: u64,
+    pub seed: u64,
+    // Seeds of the other renders merged into this one
+    pub merged_seeds: Vec<u64>,
+    pub sampler: SamplerKind,
+    pub samples_per_pixel: u32,
+    pub samples_per_pass: u32,
+    // Which pixels take more samples depends on it as much as on the seed
+    pub adaptive: Option<AdaptiveSampling>,
+    // Passes rendered so far
+    pub passes: u32,
+    // Index of the next sample of every pixel
+    pub next_sample: Vec<u32>,
+    pub image: Image,
+    pub features: FeatureBuffers,
+}
+
+const MAGIC: &[u8; 4] = b"RCKP";
+const VERSION: u32 = 3;
+// Longer than any sampler's name
+const MAX_NAME_LENGTH: usize = 64;
+// Bytes per pixel after the header: the next sample index, 7 values for
+// each of the image and the 7 feature images, then the object and
+// material IDs
+const PIXEL_BYTES: usize = 4 + 8 * 7 * 4 + 2 * 4;
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_f32(reader: &mut impl Read) -> Result<f32> {
+    Ok(f32::from_le_bytes(read_bytes(reader)?))
+}
+
+// The accumulated sums and statistics of every pixel, not the averages, so
+// more samples can be added after reading it back
+fn write_image(writer: &mut impl Write, image: &Image) -> Result<()> {
+    for i in 0..image.pixels.len() {
+        let pixel = image.pixels[i];
+        for value in [pixel.x, pixel.y, pixel.z, image.weights[i]] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&image.samples[i].to_le_bytes())?;
+        writer.write_all(&image.luminance_mean[i].to_le_bytes())?;
+        writer.write_all(&image.luminance_m2[i].to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_image(reader: &mut impl Read, width: usize, height: usize) -> Result<Image> {
+    let mut image = Image::new(width, height);
+    for i in 0..width * height {
+        image.pixels[i] = Color {
+            x: read_f32(reader)?,
+            y: read_f32(reader)?,
+            z: read_f32(reader)?,
+        };
+        image.weights[i] = read_f32(reader)?;
+        image.samples[i] = read_u32(reader)?;
+        image.luminance_mean[i] = read_f32(reader)?;
+        image.luminance_m2[i] = read_f32(reader)?;
+    }
+    Ok(image)
+}
+
+impl Checkpoint {
+    pub fn width(&self) -> usize {
+        self.image.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.image.height
+    }
+
+    // Little endian binary: a header with the settings that have to match
+    // to continue, then the accumulated image and features
+    pub fn write(&self, mut writer: impl Write) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.scene_hash.to_le_bytes())?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        let sampler = self.sampler.name().as_bytes();
+        writer.write_all(&(sampler.len() as u32).to_le_bytes())?;
+        writer.write_all(sampler)?;
+        for value in [
+            self.samples_per_pixel,
+            self.samples_per_pass,
+            self.passes,
+            self.width() as u32,
+            self.height() as u32,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        // A flag, then the minimum sample count and threshold
+        let (adaptive, min_samples, threshold) = match self.adaptive {
+            Some(adaptive) => (1u32, adaptive.min_samples, adaptive.threshold),
+            None => (0, 0, 0.0f32),
+        };
+        writer.write_all(&adaptive.to_le_bytes())?;
+        writer.write_all(&min_samples.to_le_bytes())?;
+        writer.write_all(&threshold.to_le_bytes())?;
+        writer.write_all(&(self.merged_seeds.len() as u32).to_le_bytes())?;
+        for seed in &self.merged_seeds {
+            writer.write_all(&seed.to_le_bytes())?;
+        }
+
+        for index in &self.next_sample {
+            writer.write_all(&index.to_le_bytes())?;
+        }
+        write_image(&mut writer, &self.image)?;
+        let features = &self.features;
+        for image in [
+            &features.albedo,
+            &features.normal,
+            &features.depth,
+            &features.position,
+            &features.emission,
+            &features.direct,
+            &features.indirect,
+        ] {
+            write_image(&mut writer, image)?;
+        }
+        for id in features.object_ids.iter().chain(&features.material_ids) {
+            writer.write_all(&id.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    // The image comes back with the default filter, the renderer puts the
+    // one from its settings back. Nothing is allocated for the pixels until
+    // they have all been read, so a corrupt file is an error rather than
+    // running out of memory.
+    pub fn read(mut reader: impl Read) -> Result<Checkpoint> {
+        if &read_bytes::<4>(&mut reader)? != MAGIC {
+            return Err(invalid("not a checkpoint".to_string()));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported checkpoint version {version}")));
+        }
+        let scene_hash = read_u64(&mut reader)?;
+        let seed = read_u64(&mut reader)?;
+        let length = read_u32(&mut reader)? as usize;
+        if length > MAX_NAME_LENGTH {
+            return Err(invalid(format!("sampler name of {length} bytes")));
+        }
+        let mut name = vec![0; length];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8_lossy(&name);
+        let sampler = SamplerKind::from_name(&name)
+            .ok_or_else(|| invalid(format!("unknown sampler '{name}'")))?;
+        let samples_per_pixel = read_u32(&mut reader)?;
+        let samples_per_pass = read_u32(&mut reader)?;
+        let passes = read_u32(&mut reader)?;
+        let width = read_u32(&mut reader)? as usize;
+        let height = read_u32(&mut reader)? as usize;
+        let adaptive = read_u32(&mut reader)? != 0;
+        let min_samples = read_u32(&mut reader)?;
+        let threshold = read_f32(&mut reader)?;
+        let adaptive = adaptive.then_some(AdaptiveSampling {
+            min_samples,
+            max_samples: samples_per_pixel,
+            threshold,
+        });
+        let merged_seeds = (0..read_u32(&mut reader)?)
+            .map(|_| read_u64(&mut reader))
+            .collect::<Result<_>>()?;
+
+        // The rest has to be exactly the pixels
+        let size = width
+            .checked_mul(height)
+            .filter(|&pixels| pixels > 0)
+            .and_then(|pixels| pixels.checked_mul(PIXEL_BYTES));
+        let Some(size) = size else {
+            return Err(invalid(format!("invalid resolution {width}x{height}")));
+        };
+        let mut body = vec![];
+        reader.take(size as u64 + 1).read_to_end(&mut body)?;
+        if body.len() != size {
+            return Err(invalid(format!(
+                "expected {size} bytes of pixels, got {}",
+                body.len()
+            )));
+        }
+        let mut reader = &body[..];
+
+        let next_sample = (0..width * height)
+            .map(|_| read_u32(&mut reader))
+            .collect::<Result<_>>()?;
+        let image = read_image(&mut reader, width, height)?;
+        let mut features = FeatureBuffers::new(width, height);
+        for buffer in [
+            &mut features.albedo,
+            &mut features.normal,
+            &mut features.depth,
+            &mut features.position,
+            &mut features.emission,
+            &mut features.direct,
+            &mut features.indirect,
+        ] {
+            *buffer = read_image(&mut reader, width, height)?;
+        }
+        for id in features
+            .object_ids
+            .iter_mut()
+            .chain(features.material_ids.iter_mut())
+        {
+            *id = read_u32(&mut reader)?;
+        }
+
+        Ok(Checkpoint {
+            scene_hash,
+            seed,
+            merged_seeds,
+            sampler,
+            samples_per_pixel,
+            samples_per_pass,
+            adaptive,
+            passes,
+            next_sample,
+            image,
+            features,
+        })
+    }
+
+    // Written next to `path` and then moved over it, so being killed while
+    // saving leaves the previous checkpoint intact
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temporary = path.with_extension("partial");
+        self.write(BufWriter::new(File::create(&temporary)?))?;
+        std::fs::rename(&temporary, path)
+    }
+
+    pub fn load(path: &Path) -> Result<Checkpoint> {
+        Checkpoint::read(BufReader::new(File::open(path)?))
+    }
+}
+
+// Combines renders of the same scene made independently, say on several
+// machines with different seeds, into one with all of their samples. No
+// seed may come up twice, even across merges, or its samples would be
+// counted twice. Each
+// pixel ends up with the sum of the samples it got in every render. The
+// result can be merged again, but not resumed, having taken all the
+// samples it is set up for.
+pub fn merge(checkpoints: Vec<Checkpoint>) -> std::result::Result<Checkpoint, String> {
+    let mut checkpoints = checkpoints.into_iter();
+    let Some(mut merged) = checkpoints.next() else {
+        return Err("nothing to merge".to_string());
+    };
+    for checkpoint in checkpoints {
+        if (checkpoint.width(), checkpoint.height()) != (merged.width(), merged.height()) {
+            return Err(format!(
+                "can't merge a {}x{} render into a {}x{} one",
+                checkpoint.width(),
+                checkpoint.height(),
+                merged.width(),
+                merged.height()
+            ));
+        }
+        if checkpoint.scene_hash != merged.scene_hash {
+            return Err(format!(
+                "scene hashes differ, {:016x} and {:016x}",
+                checkpoint.scene_hash, merged.scene_hash
+            ));
+        }
+        let mut seeds = std::iter::once(&merged.seed).chain(&merged.merged_seeds);
+        let other_seeds: Vec<u64> = std::iter::once(checkpoint.seed)
+            .chain(checkpoint.merged_seeds)
+            .collect();
+        if let Some(seed) = seeds.find(|seed| other_seeds.contains(seed)) {
+            return Err(format!("two renders used seed {seed}"));
+        }
+        merged.merged_seeds.extend(other_seeds);
+
+        merged.samples_per_pixel += checkpoint.samples_per_pixel;
+        merged.passes += checkpoint.passes;
+        for (next, other) in merged.next_sample.iter_mut().zip(&checkpoint.next_sample) {
+            *next += other;
+        }
+        merged.image.merge(&checkpoint.image);
+        merged.features.merge(&checkpoint.features);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checkpoint::*;
+    use crate::color::luminance;
+
+    fn checkpoint(scene_hash: u64, seed: u64, width: usize, samples: &[f32]) -> Checkpoint {
+        let mut image = Image::new(width, 1);
+        for &value in samples {
+            image.add_sample(
+                0,
+                0,
+                Color {
+                    x: value,
+                    y: value,
+                    z: value,
+                },
+            );
+        }
+        Checkpoint {
+            scene_hash,
+            seed,
+            merged_seeds: vec![],
+            sampler: SamplerKind::Sobol,
+            samples_per_pixel: samples.len() as u32,
+            samples_per_pass: samples.len() as u32,
+            adaptive: None,
+            passes: 1,
+            next_sample: vec![samples.len() as u32; width],
+            image,
+            features: FeatureBuffers::new(width, 1),
+        }
+    }
+
+    #[test]
+    fn test_merge_matches_one_render_with_all_samples() {
+        let merged = merge(vec![
+            checkpoint(1, 0, 2, &[0.1, 0.7]),
+            checkpoint(1, 1, 2, &[0.3, 0.2, 0.9]),
+            checkpoint(1, 2, 2, &[0.5]),
+        ])
+        .unwrap();
+        let single = checkpoint(1, 0, 2, &[0.1, 0.7, 0.3, 0.2, 0.9, 0.5]);
+
+        assert_eq!(merged.image.samples, vec![6, 0]);
+        assert_eq!(merged.samples_per_pixel, 6);
+        assert!(
+            (luminance(&merged.image.get(0, 0)) - luminance(&single.image.get(0, 0))).abs() < 1e-5
+        );
+        assert!((merged.image.variance(0, 0) - single.image.variance(0, 0)).abs() < 1e-5);
+
+        assert!(merge(vec![
+            checkpoint(1, 0, 2, &[0.1]),
+            checkpoint(1, 1, 3, &[0.1])
+        ])
+        .is_err());
+        assert!(merge(vec![
+            checkpoint(1, 0, 2, &[0.1]),
+            checkpoint(2, 1, 2, &[0.1])
+        ])
+        .is_err());
+        assert!(merge(vec![
+            checkpoint(1, 0, 2, &[0.1]),
+            checkpoint(1, 0, 2, &[0.1])
+        ])
+        .is_err());
+        assert!(merge(vec![]).is_err());
+
+        // Merged seeds carry over, a render with one of them is still caught
+        let mut bytes = vec![];
+        merged.write(&mut bytes).unwrap();
+        let merged = Checkpoint::read(&bytes[..]).unwrap();
+        assert_eq!(merged.merged_seeds, vec![1, 2]);
+        assert!(merge(vec![merged, checkpoint(1, 1, 2, &[0.4])]).is_err());
+
+        // Corrupt files are errors, without allocating what they claim
+        assert!(Checkpoint::read(&bytes[..bytes.len() - 1]).is_err());
+        let width = 4 + 4 + 8 + 8 + 4 + "sobol".len() + 3 * 4;
+        assert_eq!(bytes[width..width + 4], 2u32.to_le_bytes());
+        bytes[width..width + 8].copy_from_slice(&[0xff; 8]);
+        assert!(Checkpoint::read(&bytes[..]).is_err());
+    }
+}
@@ -1,26 +1,21 @@
 pub use crate::vector::Vec3;
-use std::io::Write;
+
+pub mod space;
+pub mod tonemap;
 
 // Make an alias for Color
 pub use Vec3 as Color;
 
-// Write the translated [0, 255] value of each component
-pub fn write_color(mut writer: impl Write, color: &Color, num_samples: u16) {
-    let mut r = color.x;
-    let mut g = color.y;
-    let mut b = color.z;
-
-    let scale = 1.0 / f32::from(num_samples);
-    r = (scale * r).sqrt();
-    g = (scale * g).sqrt();
-    b = (scale * b).sqrt();
+// Perceived brightness of a linear color
+pub fn luminance(color: &Color) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
 
-    writeln!(
-        writer,
-        "{} {} {}",
-        (r.clamp(0.0, 0.999) * 255.99) as u8,
-        (g.clamp(0.0, 0.999) * 255.99) as u8,
-        (b.clamp(0.0, 0.999) * 255.99) as u8
-    )
-    .expect("We should be allowed to write");
+// sRGB transfer function, linear [0, 1] to the encoded value displays expect
+pub fn srgb_encode(linear: f32) -> f32 {
+    if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
 }
@@ -0,0 +1,263 @@
+use std::sync::OnceLock;
+
+use crate::color::{srgb_encode, Color};
+
+// Rows of a 3x3 matrix acting on column vectors
+pub type Matrix = [[f32; 3]; 3];
+
+pub fn transform(m: &Matrix, c: &Color) -> Color {
+    Color {
+        x: m[0][0] * c.x + m[0][1] * c.y + m[0][2] * c.z,
+        y: m[1][0] * c.x + m[1][1] * c.y + m[1][2] * c.z,
+        z: m[2][0] * c.x + m[2][1] * c.y + m[2][2] * c.z,
+    }
+}
+
+// Matrix math is done in doubles, the results are only rounded to floats
+// at the end.
+type Matrix64 = [[f64; 3]; 3];
+
+fn multiply(a: &Matrix64, b: &Matrix64) -> Matrix64 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn inverse(m: &Matrix64) -> Matrix64 {
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f64 = (0..3).map(|j| m[0][j] * cofactor(0, j)).sum();
+    let mut inverse = [[0.0; 3]; 3];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = cofactor(j, i) / determinant;
+        }
+    }
+    inverse
+}
+
+fn apply(m: &Matrix64, v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+fn to_f32(m: &Matrix64) -> Matrix {
+    m.map(|row| row.map(|value| value as f32))
+}
+
+// XYZ of a chromaticity with a luminance of one
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+// Bradford chromatic adaptation, maps colors seen under one white point to
+// the corresponding colors under another.
+// http://www.brucelindbloom.com/index.html?Eqn_ChromAdapt.html
+const BRADFORD: Matrix64 = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+fn chromatic_adaptation(from: (f64, f64), to: (f64, f64)) -> Matrix64 {
+    let source = apply(&BRADFORD, xy_to_xyz(from));
+    let destination = apply(&BRADFORD, xy_to_xyz(to));
+    let mut scale = [[0.0; 3]; 3];
+    for i in 0..3 {
+        scale[i][i] = destination[i] / source[i];
+    }
+    multiply(&inverse(&BRADFORD), &multiply(&scale, &BRADFORD))
+}
+
+const D65: (f64, f64) = (0.3127, 0.3290);
+const ACES_WHITE: (f64, f64) = (0.32168, 0.33767);
+
+// RGB color spaces, defined by the chromaticities of their primaries and
+// white point. Rendering happens in `WORKING_SPACE`, colors authored in
+// other spaces are converted on the way in and the image is converted to
+// the display's space on the way out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    // sRGB and Rec.709 share primaries and white point
+    Srgb,
+    // ACES AP1 primaries with the ACES white point, for CG work
+    AcesCg,
+    DisplayP3,
+    Rec2020,
+}
+
+// The spectral code and the sky assume sRGB primaries
+pub const WORKING_SPACE: ColorSpace = ColorSpace::Srgb;
+
+impl ColorSpace {
+    pub const ALL: [ColorSpace; 4] = [
+        ColorSpace::Srgb,
+        ColorSpace::AcesCg,
+        ColorSpace::DisplayP3,
+        ColorSpace::Rec2020,
+    ];
+
+    pub fn from_name(name: &str) -> Option<ColorSpace> {
+        match name {
+            "srgb" | "rec709" => Some(ColorSpace::Srgb),
+            "acescg" => Some(ColorSpace::AcesCg),
+            "p3" | "display-p3" => Some(ColorSpace::DisplayP3),
+            "rec2020" => Some(ColorSpace::Rec2020),
+            _ => None,
+        }
+    }
+
+    // Red, green and blue primaries, then the white point, as CIE xy
+    pub fn chromaticities(&self) -> [(f64, f64); 4] {
+        match self {
+            ColorSpace::Srgb => [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06), D65],
+            ColorSpace::AcesCg => [(0.713, 0.293), (0.165, 0.830), (0.128, 0.044), ACES_WHITE],
+            ColorSpace::DisplayP3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060), D65],
+            ColorSpace::Rec2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046), D65],
+        }
+    }
+
+    pub fn white_point(&self) -> (f64, f64) {
+        self.chromaticities()[3]
+    }
+
+    // Linear RGB to XYZ, each primary's column scaled so that RGB white
+    // lands on the white point
+    fn rgb_to_xyz64(&self) -> Matrix64 {
+        let [r, g, b, white] = self.chromaticities();
+        let [r, g, b] = [r, g, b].map(xy_to_xyz);
+        let unscaled = [0, 1, 2].map(|i| [r[i], g[i], b[i]]);
+        let scale = apply(&inverse(&unscaled), xy_to_xyz(white));
+        unscaled.map(|row| [row[0] * scale[0], row[1] * scale[1], row[2] * scale[2]])
+    }
+
+    pub fn rgb_to_xyz(&self) -> Matrix {
+        to_f32(&self.rgb_to_xyz64())
+    }
+
+    pub fn xyz_to_rgb(&self) -> Matrix {
+        to_f32(&inverse(&self.rgb_to_xyz64()))
+    }
+
+    // Linear RGB in this space to linear RGB in `other`, adapting the white
+    // point when they differ
+    pub fn conversion(&self, other: ColorSpace) -> Matrix {
+        let adaptation = chromatic_adaptation(self.white_point(), other.white_point());
+        let m = multiply(
+            &inverse(&other.rgb_to_xyz64()),
+            &multiply(&adaptation, &self.rgb_to_xyz64()),
+        );
+        to_f32(&m)
+    }
+
+    pub fn convert(&self, color: &Color, other: ColorSpace) -> Color {
+        if *self == other {
+            return *color;
+        }
+        transform(&conversions()[self.index()][other.index()], color)
+    }
+
+    // A linear color authored in this space, in the working space
+    pub fn rgb(&self, r: f32, g: f32, b: f32) -> Color {
+        self.convert(&Color { x: r, y: g, z: b }, WORKING_SPACE)
+    }
+
+    // Transfer function from linear values to what displays in this space
+    // expect. ACEScg isn't meant for displays and stays linear.
+    pub fn encode(&self, linear: f32) -> f32 {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => srgb_encode(linear),
+            // Rec.2020 uses the Rec.709 camera curve
+            ColorSpace::Rec2020 => {
+                if linear < 0.0181 {
+                    4.5 * linear
+                } else {
+                    1.0993 * linear.powf(0.45) - 0.0993
+                }
+            }
+            ColorSpace::AcesCg => linear,
+        }
+    }
+
+    fn index(&self) -> usize {
+        ColorSpace::ALL
+            .iter()
+            .position(|space| space == self)
+            .unwrap()
+    }
+}
+
+// Every pairwise conversion, worked out once
+fn conversions() -> &'static [[Matrix; 4]; 4] {
+    static CONVERSIONS: OnceLock<[[Matrix; 4]; 4]> = OnceLock::new();
+    CONVERSIONS
+        .get_or_init(|| ColorSpace::ALL.map(|from| ColorSpace::ALL.map(|to| from.conversion(to))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::space::*;
+    use crate::vector::Length;
+
+    fn assert_matrix_eq(a: &Matrix, b: &Matrix, tolerance: f32) {
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((a[i][j] - b[i][j]).abs() < tolerance, "{a:?} != {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_srgb_to_xyz() {
+        let expected = [
+            [0.4124, 0.3576, 0.1805],
+            [0.2126, 0.7152, 0.0722],
+            [0.0193, 0.1192, 0.9505],
+        ];
+        assert_matrix_eq(&ColorSpace::Srgb.rgb_to_xyz(), &expected, 1e-3);
+    }
+
+    #[test]
+    fn test_srgb_to_acescg() {
+        // Reference values with Bradford adaptation from D65 to the ACES white
+        let expected = [
+            [0.6131, 0.3395, 0.0474],
+            [0.0702, 0.9164, 0.0134],
+            [0.0206, 0.1096, 0.8698],
+        ];
+        assert_matrix_eq(
+            &ColorSpace::Srgb.conversion(ColorSpace::AcesCg),
+            &expected,
+            1e-3,
+        );
+    }
+
+    #[test]
+    fn test_round_trips_and_white() {
+        let white = Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let color = Color {
+            x: 0.2,
+            y: 0.5,
+            z: 0.9,
+        };
+        for from in ColorSpace::ALL {
+            for to in ColorSpace::ALL {
+                let converted = from.convert(&white, to);
+                assert!((converted - white).length() < 1e-4);
+
+                let back = to.convert(&from.convert(&color, to), from);
+                assert!((back - color).length() < 1e-4);
+            }
+        }
+    }
+}
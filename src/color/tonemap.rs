@@ -0,0 +1,247 @@
+use crate::color::space::*;
+use crate::color::{luminance, Color};
+
+// Squeezes scene radiance, which can be arbitrarily bright, into the [0, 1]
+// range of a display. Results are linear, sRGB encoding comes after.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapper {
+    // No tone mapping, anything above one clips
+    Clamp,
+    // L / (1 + L) on luminance, which never quite reaches white
+    Reinhard,
+    // Reinhard reaching white at a luminance of `white`
+    ExtendedReinhard { white: f32 },
+    // Stephen Hill's fit of the ACES reference rendering and output
+    // transforms, the usual filmic look
+    // https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+    AcesFilmic,
+    // Desaturates towards white in the highlights instead of skewing hues,
+    // minimal version with a polynomial fit of the default contrast curve
+    // https://iolite-engine.com/blog_posts/minimal_agx_implementation
+    AgX,
+}
+
+impl ToneMapper {
+    pub fn from_name(name: &str, white: f32) -> Option<ToneMapper> {
+        match name {
+            "clamp" => Some(ToneMapper::Clamp),
+            "reinhard" => Some(ToneMapper::Reinhard),
+            "extended-reinhard" => Some(ToneMapper::ExtendedReinhard { white }),
+            "aces" => Some(ToneMapper::AcesFilmic),
+            "agx" => Some(ToneMapper::AgX),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, color: &Color) -> Color {
+        let mapped = match self {
+            ToneMapper::Clamp => *color,
+            ToneMapper::Reinhard => scale_luminance(color, |l| l / (1.0 + l)),
+            ToneMapper::ExtendedReinhard { white } => {
+                scale_luminance(color, |l| l * (1.0 + l / (white * white)) / (1.0 + l))
+            }
+            ToneMapper::AcesFilmic => aces_filmic(color),
+            ToneMapper::AgX => agx(color),
+        };
+        clamp(&mapped)
+    }
+}
+
+fn clamp(color: &Color) -> Color {
+    Color {
+        x: color.x.clamp(0.0, 1.0),
+        y: color.y.clamp(0.0, 1.0),
+        z: color.z.clamp(0.0, 1.0),
+    }
+}
+
+fn scale_luminance(color: &Color, curve: impl Fn(f32) -> f32) -> Color {
+    let l = luminance(color);
+    if l <= 0.0 {
+        return Color::zero();
+    }
+    *color * (curve(l) / l)
+}
+
+fn map(color: &Color, f: impl Fn(f32) -> f32) -> Color {
+    Color {
+        x: f(color.x),
+        y: f(color.y),
+        z: f(color.z),
+    }
+}
+
+// sRGB to the ACES rendering space with the reference look modification
+// folded in, and back to sRGB
+const ACES_INPUT: Matrix = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+const ACES_OUTPUT: Matrix = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+fn aces_filmic(color: &Color) -> Color {
+    let v = transform(&ACES_INPUT, color);
+    let v = map(&v, |v| {
+        (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081)
+    });
+    transform(&ACES_OUTPUT, &v)
+}
+
+// Into and out of the AgX log encoding space, a slightly rotated and
+// inset version of sRGB
+const AGX_INSET: Matrix = [
+    [0.842479, 0.0784336, 0.0792237],
+    [0.0423282, 0.878469, 0.0791661],
+    [0.0423757, 0.0784336, 0.879143],
+];
+const AGX_OUTSET: Matrix = [
+    [1.196879, -0.0980209, -0.0990297],
+    [-0.0528969, 1.151903, -0.0989612],
+    [-0.0529716, -0.0980435, 1.151074],
+];
+const AGX_MIN_EV: f32 = -12.47393;
+const AGX_MAX_EV: f32 = 4.026069;
+
+fn agx(color: &Color) -> Color {
+    let v = transform(&AGX_INSET, color);
+    let v = map(&v, |v| {
+        let x = (v.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV) - AGX_MIN_EV)
+            / (AGX_MAX_EV - AGX_MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+    // The curve produces display encoded values, undo the 2.2 gamma it
+    // assumes so the sRGB encoding can be applied like for the others
+    let v = transform(&AGX_OUTSET, &v);
+    map(&v, |v| v.max(0.0).powf(2.2))
+}
+
+// 8x8 Bayer matrix thresholds in [0, 1)
+fn bayer(x: usize, y: usize) -> f32 {
+    let mut value = 0;
+    for bit in 0..3 {
+        let bx = (x >> bit) & 1;
+        let by = (y >> bit) & 1;
+        // Each level interleaves the pattern 0 2 / 3 1
+        value |= (((bx ^ by) << 1) | by) << (2 * (2 - bit));
+    }
+    (value as f32 + 0.5) / 64.0
+}
+
+// Everything between linear radiance in the film and 8 bit display values.
+// Tone mapping happens in the working space, the result is then converted
+// to the display's primaries and encoded with its transfer function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputTransform {
+    // In stops, every +1 doubles the brightness
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    pub display: ColorSpace,
+    // Ordered dithering before quantizing, breaks up banding in gradients
+    pub dither: bool,
+}
+
+impl Default for OutputTransform {
+    fn default() -> Self {
+        OutputTransform {
+            exposure: 0.0,
+            tone_mapper: ToneMapper::Clamp,
+            display: ColorSpace::Srgb,
+            dither: false,
+        }
+    }
+}
+
+impl OutputTransform {
+    // Display referred linear color in [0, 1], in the display's space.
+    // Colors outside of its gamut are clipped.
+    pub fn apply(&self, color: &Color) -> Color {
+        let mapped = self.tone_mapper.apply(&(*color * self.exposure.exp2()));
+        clamp(&WORKING_SPACE.convert(&mapped, self.display))
+    }
+
+    // Encoded color in [0, 1] as the display expects it
+    pub fn encode(&self, color: &Color) -> Color {
+        map(&self.apply(color), |v| self.display.encode(v))
+    }
+
+    // 8 bit display values for pixel (x, y)
+    pub fn quantize(&self, color: &Color, x: usize, y: usize) -> [u8; 3] {
+        let offset = if self.dither {
+            bayer(x % 8, y % 8)
+        } else {
+            0.5
+        };
+        let encoded = self.encode(color);
+        [encoded.x, encoded.y, encoded.z]
+            .map(|v| (v * 255.0 + offset).floor().clamp(0.0, 255.0) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::srgb_encode;
+    use crate::color::tonemap::*;
+
+    #[test]
+    fn test_srgb_encoding() {
+        assert_eq!(srgb_encode(0.0), 0.0);
+        assert!((srgb_encode(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb_encode(0.5) - 0.735357).abs() < 1e-5);
+        // Both pieces meet at the threshold
+        assert!((12.92 * 0.0031308 - (1.055 * 0.0031308_f32.powf(1.0 / 2.4) - 0.055)).abs() < 1e-5);
+
+        let transform = OutputTransform::default();
+        let gray = Color {
+            x: 0.5,
+            y: 0.5,
+            z: 0.5,
+        };
+        assert_eq!(transform.quantize(&gray, 0, 0), [188, 188, 188]);
+    }
+
+    #[test]
+    fn test_tone_mappers() {
+        let one = Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        assert!((ToneMapper::Reinhard.apply(&one).x - 0.5).abs() < 1e-6);
+        let white = Color {
+            x: 4.0,
+            y: 4.0,
+            z: 4.0,
+        };
+        assert!((ToneMapper::ExtendedReinhard { white: 4.0 }.apply(&white).x - 1.0).abs() < 1e-5);
+
+        // The filmic curves are monotonic, black stays black and very bright
+        // values come close to white
+        for mapper in [ToneMapper::AcesFilmic, ToneMapper::AgX] {
+            let mut previous = -1.0;
+            for i in 0..64 {
+                let value = mapper.apply(&(one * (0.01 * 1.2_f32.powi(i)))).y;
+                assert!(value >= previous);
+                previous = value;
+            }
+            assert!(mapper.apply(&Color::zero()).y < 0.01);
+            assert!(mapper.apply(&(one * 1000.0)).y > 0.95);
+        }
+    }
+
+    #[test]
+    fn test_bayer_covers_every_threshold() {
+        let mut thresholds: Vec<u32> = (0..64)
+            .map(|i| (bayer(i % 8, i / 8) * 64.0) as u32)
+            .collect();
+        thresholds.sort();
+        assert_eq!(thresholds, (0..64).collect::<Vec<u32>>());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::aov::FeatureBuffers;
+use crate::color::{luminance, Color};
+use crate::image::Image;
+use crate::vector::*;
+
+// Edge avoiding a-trous wavelet filter. Each pass blurs with a 5x5 B3
+// spline kernel whose taps are spread twice as far apart as in the previous
+// pass, and every tap is weighted down where illumination, normal, depth or
+// albedo differ from the center pixel so edges stay sharp.
+// https://jo.dreggn.org/home/2010_atrous.pdf
+//
+// Lighting is filtered with the albedo divided out, so textures don't get
+// blurred away with the noise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Denoiser {
+    pub iterations: u32,
+    // Tolerated difference in illumination luminance, relative to the
+    // brighter of the two pixels and halved every pass
+    pub sigma_color: f32,
+    // Exponent on the cosine between normals
+    pub sigma_normal: f32,
+    // Tolerated relative difference in depth per pixel of distance
+    pub sigma_depth: f32,
+    pub sigma_albedo: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Denoiser {
+            iterations: 5,
+            sigma_color: 1.0,
+            sigma_normal: 64.0,
+            sigma_depth: 0.05,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Keeps black albedos from blowing up the demodulated lighting
+const MIN_ALBEDO: f32 = 0.01;
+
+struct Guide {
+    albedo: Color,
+    normal: Vec3,
+    depth: f32,
+}
+
+fn demodulation(albedo: &Color) -> Color {
+    Color {
+        x: albedo.x.max(MIN_ALBEDO),
+        y: albedo.y.max(MIN_ALBEDO),
+        z: albedo.z.max(MIN_ALBEDO),
+    }
+}
+
+impl Denoiser {
+    pub fn denoise(&self, image: &Image, features: &FeatureBuffers) -> Image {
+        let (width, height) = (image.width, image.height);
+
+        let guides: Vec<Guide> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let normal = features.normal.get(x, y);
+                Guide {
+                    albedo: features.albedo.get(x, y),
+                    normal: if normal.near_zero() {
+                        Vec3::zero()
+                    } else {
+                        unit_vector(&normal)
+                    },
+                    depth: features.depth.get(x, y).x,
+                }
+            })
+            .collect();
+
+        let mut illumination: Vec<Color> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let mut color = image.get(x, y);
+                color /= demodulation(&guides[image.index(x, y)].albedo);
+                color
+            })
+            .collect();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let sigma_color = self.sigma_color / (1 << iteration) as f32;
+            let mut filtered = vec![Color::zero(); illumination.len()];
+
+            for y in 0..height {
+                for x in 0..width {
+                    let center = image.index(x, y);
+                    let mut sum = Color::zero();
+                    let mut total = 0.0;
+
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let dx = (i as isize - 2) * step;
+                            let dy = (j as isize - 2) * step;
+                            let qx = x as isize + dx;
+                            let qy = y as isize + dy;
+                            if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                                continue;
+                            }
+                            let other = image.index(qx as usize, qy as usize);
+                            let distance = ((dx * dx + dy * dy) as f32).sqrt();
+                            let weight = kx
+                                * ky
+                                * self.weight(&guides[center], &guides[other], distance)
+                                * self.color_weight(
+                                    &illumination[center],
+                                    &illumination[other],
+                                    sigma_color,
+                                );
+                            sum += weight * illumination[other];
+                            total += weight;
+                        }
+                    }
+
+                    filtered[center] = if total > 0.0 {
+                        sum / total
+                    } else {
+                        illumination[center]
+                    };
+                }
+            }
+            illumination = filtered;
+        }
+
+        let mut output = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let index = image.index(x, y);
+                output.add_sample(
+                    x,
+                    y,
+                    illumination[index] * demodulation(&guides[index].albedo),
+                );
+            }
+        }
+        output.samples.clone_from(&image.samples);
+        output
+    }
+
+    // Similarity of the surfaces seen by two pixels `distance` apart
+    fn weight(&self, center: &Guide, other: &Guide, distance: f32) -> f32 {
+        // Sky only blends with sky
+        let center_miss = center.normal.near_zero();
+        if center_miss != other.normal.near_zero() {
+            return 0.0;
+        }
+        if center_miss {
+            return 1.0;
+        }
+
+        let normal = dot(&center.normal, &other.normal)
+            .max(0.0)
+            .powf(self.sigma_normal);
+
+        let depth_difference = (center.depth - other.depth).abs();
+        let depth = (-depth_difference / (self.sigma_depth * center.depth * distance + 1e-4)).exp();
+
+        let albedo_difference = (center.albedo - other.albedo).length_squared();
+        let albedo = (-albedo_difference / (self.sigma_albedo * self.sigma_albedo)).exp();
+
+        normal * depth * albedo
+    }
+
+    fn color_weight(&self, center: &Color, other: &Color, sigma: f32) -> f32 {
+        let a = luminance(center);
+        let b = luminance(other);
+        let difference = (a - b).abs() / a.max(b).max(MIN_ALBEDO);
+        (-difference * difference / (sigma * sigma)).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::denoise::*;
+    use crate::integrator::Features;
+
+    #[test]
+    fn test_denoise_flattens_noise_but_keeps_edges() {
+        // Left half faces one way, right half another, with checkerboard
+        // noise on top of constant lighting
+        let (width, height) = (16, 8);
+        let mut image = Image::new(width, height);
+        let mut features = FeatureBuffers::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let left = x < width / 2;
+                let level = if left { 0.2 } else { 0.8 };
+                let noise = if (x + y) % 2 == 0 { 0.05 } else { -0.05 };
+                let value = level + noise;
+                image.add_sample(
+                    x,
+                    y,
+                    Color {
+                        x: value,
+                        y: value,
+                        z: value,
+                    },
+                );
+                features.add(
+                    x,
+                    y,
+                    &Features {
+                        normal: if left {
+                            Vec3 {
+                                x: 1.0,
+                                y: 0.0,
+                                z: 0.0,
+                            }
+                        } else {
+                            Vec3 {
+                                x: 0.0,
+                                y: 1.0,
+                                z: 0.0,
+                            }
+                        },
+                        depth: 1.0,
+                        ..Features::miss()
+                    },
+                );
+            }
+        }
+
+        let denoised = Denoiser::default().denoise(&image, &features);
+        for y in 0..height {
+            for x in 0..width {
+                let expected = if x < width / 2 { 0.2 } else { 0.8 };
+                assert!((denoised.get(x, y).x - expected).abs() < 0.02);
+            }
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::io::{Result, Write};
+
+use crate::color::space::WORKING_SPACE;
+use crate::color::tonemap::OutputTransform;
+use crate::image::{Image, PixelBounds};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // Plain text 8 bit PPM, gamma encoded for display
+    Ppm,
+    // Portable float map, linear 32 bit floats for further processing
+    Pfm,
+    // OpenEXR, linear 32 bit floats that can carry AOVs as extra layers
+    Exr,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "ppm" => Some(Format::Ppm),
+            "pfm" => Some(Format::Pfm),
+            "exr" => Some(Format::Exr),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Ppm => "ppm",
+            Format::Pfm => "pfm",
+            Format::Exr => "exr",
+        }
+    }
+}
+
+// Only the 8 bit format goes through the output transform, the float ones
+// keep scene linear values for further processing.
+pub fn encode(
+    writer: impl Write,
+    image: &Image,
+    format: Format,
+    transform: &OutputTransform,
+) -> Result<()> {
+    match format {
+        Format::Ppm => write_ppm(writer, image, transform),
+        Format::Pfm => write_pfm(writer, image),
+        Format::Exr => write_exr(writer, &rgb_channels(image, "")),
+    }
+}
+
+// https://netpbm.sourceforge.net/doc/ppm.html
+pub fn write_ppm(mut writer: impl Write, image: &Image, transform: &OutputTransform) -> Result<()> {
+    write!(writer, "P3\n{} {}\n255\n", image.width, image.height)?;
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let [r, g, b] = transform.quantize(&image.get(x, y), x, y);
+            writeln!(writer, "{r} {g} {b}")?;
+        }
+    }
+    writer.flush()
+}
+
+// https://www.pauldebevec.com/Research/HDR/PFM/
+// A negative scale means little endian, and rows go from bottom to top.
+pub fn write_pfm(mut writer: impl Write, image: &Image) -> Result<()> {
+    write!(writer, "PF\n{} {}\n-1.0\n", image.width, image.height)?;
+    for y in (0..image.height).rev() {
+        for x in 0..image.width {
+            let color = image.get(x, y);
+            writer.write_all(&color.x.to_le_bytes())?;
+            writer.write_all(&color.y.to_le_bytes())?;
+            writer.write_all(&color.z.to_le_bytes())?;
+        }
+    }
+    writer.flush()
+}
+
+// One channel of an EXR file, taken from a component (0 to 2) of an image
+pub struct Channel<'a> {
+    pub name: String,
+    pub image: &'a Image,
+    pub component: usize,
+}
+
+// R, G and B channels of `image`, in the layer `layer` unless it is empty
+pub fn rgb_channels<'a>(image: &'a Image, layer: &str) -> Vec<Channel<'a>> {
+    ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(component, name)| Channel {
+            name: if layer.is_empty() {
+                name.to_string()
+            } else {
+                format!("{layer}.{name}")
+            },
+            image,
+            component,
+        })
+        .collect()
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(bounds: &PixelBounds) -> Vec<u8> {
+    // Corners are inclusive
+    [
+        bounds.x0 as i32,
+        bounds.y0 as i32,
+        bounds.x1 as i32 - 1,
+        bounds.y1 as i32 - 1,
+    ]
+    .iter()
+    .flat_map(|value| value.to_le_bytes())
+    .collect()
+}
+
+// Uncompressed single part scanline OpenEXR with 32 bit float channels, in
+// the working color space. All channels must come from images of the same
+// size.
+// https://openexr.com/en/latest/OpenEXRFileLayout.html
+pub fn write_exr(writer: impl Write, channels: &[Channel]) -> Result<()> {
+    let (width, height) = match channels.first() {
+        Some(channel) => (channel.image.width, channel.image.height),
+        None => (0, 0),
+    };
+    write_exr_window(
+        writer,
+        channels,
+        width,
+        height,
+        &PixelBounds::full(width, height),
+    )
+}
+
+// EXR whose channels only cover `data_window` of a `width` by `height`
+// frame, as for a crop of a larger render. Readers show the rest as empty.
+pub fn write_exr_window(
+    mut writer: impl Write,
+    channels: &[Channel],
+    width: usize,
+    height: usize,
+    data_window: &PixelBounds,
+) -> Result<()> {
+    // Readers expect channels in alphabetical order
+    let mut channels: Vec<&Channel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut list = vec![];
+    for channel in &channels {
+        list.extend_from_slice(channel.name.as_bytes());
+        list.push(0);
+        // FLOAT pixels, not perceptually linear, reserved, no subsampling
+        list.extend_from_slice(&2_i32.to_le_bytes());
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1_i32.to_le_bytes());
+        list.extend_from_slice(&1_i32.to_le_bytes());
+    }
+    list.push(0);
+
+    let mut header = vec![];
+    header.extend_from_slice(&20000630_i32.to_le_bytes());
+    header.extend_from_slice(&2_i32.to_le_bytes());
+    let chromaticities: Vec<u8> = WORKING_SPACE
+        .chromaticities()
+        .iter()
+        .flat_map(|&(x, y)| [x as f32, y as f32])
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    write_attribute(&mut header, "channels", "chlist", &list);
+    write_attribute(
+        &mut header,
+        "chromaticities",
+        "chromaticities",
+        &chromaticities,
+    );
+    write_attribute(&mut header, "compression", "compression", &[0]);
+    write_attribute(&mut header, "dataWindow", "box2i", &box2i(data_window));
+    write_attribute(
+        &mut header,
+        "displayWindow",
+        "box2i",
+        &box2i(&PixelBounds::full(width, height)),
+    );
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    write_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    write_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    header.push(0);
+    writer.write_all(&header)?;
+
+    // Offset table pointing at each scanline, which is its y coordinate and
+    // size followed by the row of every channel in turn
+    let (data_width, data_height) = (data_window.width(), data_window.height());
+    let line_size = data_width * channels.len() * 4;
+    let first_line = header.len() + data_height * 8;
+    for y in 0..data_height {
+        let offset = (first_line + y * (line_size + 8)) as u64;
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+
+    for y in 0..data_height {
+        writer.write_all(&((data_window.y0 + y) as i32).to_le_bytes())?;
+        writer.write_all(&(line_size as i32).to_le_bytes())?;
+        for channel in &channels {
+            for x in 0..data_width {
+                let color = channel.image.get(x, y);
+                let value = [color.x, color.y, color.z][channel.component];
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::tonemap::OutputTransform;
+    use crate::color::Color;
+    use crate::encoder::*;
+
+    fn gradient() -> Image {
+        let mut image = Image::new(3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                image.add_sample(
+                    x,
+                    y,
+                    Color {
+                        x: x as f32 * 0.25,
+                        y: y as f32 * 0.5,
+                        z: 2.0,
+                    },
+                );
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_pfm_round_trip() {
+        let image = gradient();
+        let mut bytes = vec![];
+        write_pfm(&mut bytes, &image).unwrap();
+
+        let header = "PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header.as_bytes());
+        let floats: Vec<f32> = bytes[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(floats.len(), 3 * 2 * 3);
+        // Rows are stored bottom to top
+        for (i, rgb) in floats.chunks(3).enumerate() {
+            let color = image.get(i % 3, 1 - i / 3);
+            assert_eq!(rgb, [color.x, color.y, color.z]);
+        }
+    }
+
+    #[test]
+    fn test_ppm_round_trip() {
+        let image = gradient();
+        let transform = OutputTransform::default();
+        let mut bytes = vec![];
+        write_ppm(&mut bytes, &image, &transform).unwrap();
+
+        let text = String::from_utf8(bytes).unwrap();
+        let mut values = text.split_whitespace();
+        assert_eq!(
+            values.by_ref().take(4).collect::<Vec<_>>(),
+            ["P3", "3", "2", "255"]
+        );
+        let values: Vec<u8> = values.map(|v| v.parse().unwrap()).collect();
+        assert_eq!(values.len(), 3 * 2 * 3);
+        for (i, rgb) in values.chunks(3).enumerate() {
+            let (x, y) = (i % 3, i / 3);
+            assert_eq!(rgb, transform.quantize(&image.get(x, y), x, y));
+        }
+        // Black stays black and anything above one clips to white
+        assert_eq!(&values[..3], [0, 0, 255]);
+    }
+
+    #[test]
+    fn test_exr_layout() {
+        let mut image = Image::new(2, 3);
+        image.add_sample(
+            1,
+            2,
+            Color {
+                x: 0.25,
+                y: 0.5,
+                z: 0.75,
+            },
+        );
+        let mut bytes = vec![];
+        write_exr(&mut bytes, &rgb_channels(&image, "")).unwrap();
+
+        assert_eq!(&bytes[0..4], &[0x76, 0x2f, 0x31, 0x01]);
+
+        // The offset of the last scanline leads to its y coordinate, then
+        // the B, G and R rows
+        let header_end = bytes.len() - 3 * (8 + 2 * 3 * 4) - 3 * 8;
+        let offset_bytes = &bytes[header_end + 16..header_end + 24];
+        let offset = u64::from_le_bytes(offset_bytes.try_into().unwrap()) as usize;
+        assert_eq!(
+            i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()),
+            2
+        );
+        let value = |i: usize| {
+            f32::from_le_bytes(
+                bytes[offset + 8 + i * 4..offset + 12 + i * 4]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        assert_eq!([value(1), value(3), value(5)], [0.75, 0.5, 0.25]);
+        assert_eq!(offset + 8 + 24, bytes.len());
+    }
+}
@@ -0,0 +1,129 @@
+use std::f32::consts::PI;
+
+// Pixel reconstruction filters. Each sample is spread over every pixel
+// within `radius` of it, weighted by the filter, and the pixel ends up as
+// the weighted average. All filters are separable, `radius` is in pixels.
+// https://pbr-book.org/4ed/Sampling_and_Reconstruction/Image_Reconstruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    // Radius 0.5 is the plain per pixel average
+    Box { radius: f32 },
+    Tent { radius: f32 },
+    // Truncated at the radius and shifted down so it reaches zero there
+    Gaussian { radius: f32, sigma: f32 },
+    // B = C = 1/3 is the recommended trade off between ringing and blur
+    Mitchell { radius: f32, b: f32, c: f32 },
+    BlackmanHarris { radius: f32 },
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    // Filter by name with sensible defaults for everything but the radius
+    pub fn from_name(name: &str, radius: f32) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box { radius }),
+            "tent" => Some(Filter::Tent { radius }),
+            "gaussian" => Some(Filter::Gaussian {
+                radius,
+                sigma: radius / 3.0,
+            }),
+            "mitchell" => Some(Filter::Mitchell {
+                radius,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            }),
+            "blackman-harris" => Some(Filter::BlackmanHarris { radius }),
+            _ => None,
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::BlackmanHarris { radius } => radius,
+        }
+    }
+
+    // Weight of a sample offset by (dx, dy) pixels from a pixel center
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let radius = self.radius();
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { .. } => radius - x,
+            Filter::Gaussian { sigma, .. } => {
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { b, c, .. } => mitchell(2.0 * x / radius, b, c),
+            Filter::BlackmanHarris { .. } => {
+                let t = (x + radius) / (2.0 * radius);
+                0.35875 - 0.48829 * (2.0 * PI * t).cos() + 0.14128 * (4.0 * PI * t).cos()
+                    - 0.01168 * (6.0 * PI * t).cos()
+            }
+        }
+    }
+}
+
+// Mitchell-Netravali cubic over [0, 2]
+// https://www.cs.utexas.edu/~fussell/courses/cs384g-fall2013/lectures/mitchell/Mitchell.pdf
+fn mitchell(x: f32, b: f32, c: f32) -> f32 {
+    let x2 = x * x;
+    let x3 = x2 * x;
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::*;
+
+    #[test]
+    fn test_filters_vanish_outside_radius() {
+        for name in ["box", "tent", "gaussian", "mitchell", "blackman-harris"] {
+            let filter = Filter::from_name(name, 1.5).unwrap();
+            assert_eq!(filter.evaluate(1.6, 0.0), 0.0, "{name}");
+            assert_eq!(filter.evaluate(0.0, -1.6), 0.0, "{name}");
+            assert!(filter.evaluate(0.0, 0.0) > 0.0, "{name}");
+            assert_eq!(
+                filter.evaluate(0.3, -0.7),
+                filter.evaluate(-0.3, 0.7),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_mitchell_is_continuous() {
+        let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+        assert!((mitchell(0.9999, b, c) - mitchell(1.0001, b, c)).abs() < 1e-3);
+        assert!(mitchell(1.9999, b, c).abs() < 1e-3);
+        // Negative lobe between 1 and 2
+        assert!(mitchell(1.5, b, c) < 0.0);
+    }
+}
@@ -1,19 +1,28 @@
-use crate::ray::Ray;
-use crate::vector::{Vec3, dot};
 use crate::material::Material;
-use std::rc::Rc;
+use crate::ray::Ray;
+use crate::vector::{dot, Vec3};
+use std::sync::Arc;
 
 pub struct HitRecord {
     pub point: Vec3,
     pub normal: Vec3,
-    pub material: Rc<dyn Material>,
+    pub material: Arc<dyn Material>,
     pub t: f32,
+    // Surface coordinates for texture lookups
+    pub u: f32,
+    pub v: f32,
     pub front_face: bool,
+    // IOR of whatever is on the other side of the surface from the
+    // material, so nested dielectrics refract relative to their neighbour.
+    // Integrators tracking media fill it in, otherwise it is air.
+    pub exterior_ior: f32,
+    // Index of the object in the scene's top level list
+    pub object_id: u32,
 }
 
 impl HitRecord {
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
-        self.front_face = dot(&ray.direction, &outward_normal) < 0.0;
+        self.front_face = dot(&ray.direction, outward_normal) < 0.0;
         self.normal = if self.front_face {
             *outward_normal
         } else {
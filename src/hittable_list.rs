@@ -15,12 +15,14 @@ impl Hittable for HittableList {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let mut temporary_record: Option<HitRecord> = None;
         let mut closest_so_far = t_max;
-        for object in &self.objects {
-            if let Some(hit) = object.hit(ray, t_min, closest_so_far) {
+        for (index, object) in self.objects.iter().enumerate() {
+            if let Some(mut hit) = object.hit(ray, t_min, closest_so_far) {
+                // Nested lists report the index of the outermost one
+                hit.object_id = index as u32;
                 closest_so_far = hit.t;
                 temporary_record = Some(hit);
             }
         }
-        return temporary_record;
+        temporary_record
     }
 }
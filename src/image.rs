@@ -0,0 +1,415 @@
+use crate::color::{luminance, Color};
+use crate::filter::Filter;
+
+// Film the renderer accumulates into. Samples are splatted into every pixel
+// under the reconstruction filter, pixels hold the running weighted sum of
+// linear radiance and of the filter weights, so more passes can be added
+// later and the average is only taken when the image is read. `samples`
+// counts the samples taken within each pixel, along with the running mean
+// and sum of squared deviations (Welford) of their luminance for estimating
+// the noise left in the pixel.
+// Row 0 is the top of the image.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub filter: Filter,
+    pub pixels: Vec<Color>,
+    pub weights: Vec<f32>,
+    pub samples: Vec<u32>,
+    pub luminance_mean: Vec<f32>,
+    pub luminance_m2: Vec<f32>,
+}
+
+// Rectangle of pixels [x0, x1) by [y0, y1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelBounds {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl PixelBounds {
+    pub fn full(width: usize, height: usize) -> PixelBounds {
+        PixelBounds {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x0..self.x1).contains(&x) && (self.y0..self.y1).contains(&y)
+    }
+
+    // Grown by `margin` pixels on every side, staying within an image of
+    // `width` by `height`
+    pub fn expand(&self, margin: usize, width: usize, height: usize) -> PixelBounds {
+        PixelBounds {
+            x0: self.x0.saturating_sub(margin),
+            y0: self.y0.saturating_sub(margin),
+            x1: (self.x1 + margin).min(width),
+            y1: (self.y1 + margin).min(height),
+        }
+    }
+}
+
+// Pixels darker than this have their error measured relative to it instead,
+// the absolute noise there is too faint to matter.
+const MIN_ERROR_LUMINANCE: f32 = 0.05;
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image::with_filter(width, height, Filter::default())
+    }
+
+    pub fn with_filter(width: usize, height: usize, filter: Filter) -> Image {
+        Image {
+            width,
+            height,
+            filter,
+            pixels: vec![Color::zero(); width * height],
+            weights: vec![0.0; width * height],
+            samples: vec![0; width * height],
+            luminance_mean: vec![0.0; width * height],
+            luminance_m2: vec![0.0; width * height],
+        }
+    }
+
+    pub fn index(&self, x: usize, y: usize) -> usize {
+        y * self.width + x
+    }
+
+    // Add a sample taken at continuous film coordinates, where pixel (x, y)
+    // covers [x, x + 1) by [y, y + 1).
+    pub fn splat(&mut self, film_x: f32, film_y: f32, color: Color) {
+        let radius = self.filter.radius();
+        let x0 = (film_x - 0.5 - radius).ceil().max(0.0) as usize;
+        let y0 = (film_y - 0.5 - radius).ceil().max(0.0) as usize;
+        let x1 = ((film_x - 0.5 + radius).floor() + 1.0).clamp(0.0, self.width as f32) as usize;
+        let y1 = ((film_y - 0.5 + radius).floor() + 1.0).clamp(0.0, self.height as f32) as usize;
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let weight = self
+                    .filter
+                    .evaluate(x as f32 + 0.5 - film_x, y as f32 + 0.5 - film_y);
+                if weight != 0.0 {
+                    let index = self.index(x, y);
+                    self.pixels[index] += weight * color;
+                    self.weights[index] += weight;
+                }
+            }
+        }
+
+        let x = film_x.floor() as usize;
+        let y = film_y.floor() as usize;
+        if x < self.width && y < self.height {
+            self.add_statistics(x, y, &color);
+        }
+    }
+
+    fn add_statistics(&mut self, x: usize, y: usize, color: &Color) {
+        let index = self.index(x, y);
+        self.samples[index] += 1;
+        let value = luminance(color);
+        let delta = value - self.luminance_mean[index];
+        self.luminance_mean[index] += delta / self.samples[index] as f32;
+        self.luminance_m2[index] += delta * (value - self.luminance_mean[index]);
+    }
+
+    // Unfiltered sample for pixel (x, y), as if it was taken at its center
+    // with a box filter.
+    pub fn add_sample(&mut self, x: usize, y: usize, color: Color) {
+        let index = self.index(x, y);
+        self.pixels[index] += color;
+        self.weights[index] += 1.0;
+        self.add_statistics(x, y, &color);
+    }
+
+    // Adds everything accumulated in `other`, an image of the same size, as
+    // if its samples had been taken in this one. Statistics are combined
+    // with Chan et al.'s parallel version of Welford's algorithm.
+    pub fn merge(&mut self, other: &Image) {
+        for i in 0..self.pixels.len() {
+            self.pixels[i] += other.pixels[i];
+            self.weights[i] += other.weights[i];
+
+            let (count, other_count) = (self.samples[i] as f32, other.samples[i] as f32);
+            if other_count > 0.0 {
+                let total = count + other_count;
+                let delta = other.luminance_mean[i] - self.luminance_mean[i];
+                self.luminance_mean[i] += delta * other_count / total;
+                self.luminance_m2[i] +=
+                    other.luminance_m2[i] + delta * delta * count * other_count / total;
+            }
+            self.samples[i] += other.samples[i];
+        }
+    }
+
+    // Unbiased sample variance of the luminance within a pixel
+    pub fn variance(&self, x: usize, y: usize) -> f32 {
+        let index = self.index(x, y);
+        let count = self.samples[index];
+        if count < 2 {
+            return 0.0;
+        }
+        self.luminance_m2[index] / (count - 1) as f32
+    }
+
+    // Standard error of the pixel mean relative to its brightness, infinite
+    // until there are enough samples to tell.
+    pub fn relative_error(&self, x: usize, y: usize) -> f32 {
+        let index = self.index(x, y);
+        let count = self.samples[index];
+        if count < 2 {
+            return f32::INFINITY;
+        }
+        let standard_error = (self.variance(x, y) / count as f32).sqrt();
+        standard_error / self.luminance_mean[index].max(MIN_ERROR_LUMINANCE)
+    }
+
+    // Estimated noise level of the pixels in `bounds`, the root mean square
+    // of their relative errors. Infinite while any has too few samples.
+    pub fn noise(&self, bounds: &PixelBounds) -> f32 {
+        let mut sum = 0.0;
+        for y in bounds.y0..bounds.y1 {
+            for x in bounds.x0..bounds.x1 {
+                sum += self.relative_error(x, y).powi(2);
+            }
+        }
+        (sum / (bounds.width() * bounds.height()).max(1) as f32).sqrt()
+    }
+
+    // Average number of samples the pixels in `bounds` got
+    pub fn average_samples(&self, bounds: &PixelBounds) -> f32 {
+        let mut sum = 0;
+        for y in bounds.y0..bounds.y1 {
+            for x in bounds.x0..bounds.x1 {
+                sum += self.samples[self.index(x, y)] as u64;
+            }
+        }
+        sum as f32 / (bounds.width() * bounds.height()).max(1) as f32
+    }
+
+    // Grayscale image of how many samples each pixel got, white being
+    // `max_samples`
+    pub fn sample_counts(&self, max_samples: u32) -> Image {
+        let mut counts = Image::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let value = self.samples[self.index(x, y)] as f32 / max_samples.max(1) as f32;
+                counts.add_sample(
+                    x,
+                    y,
+                    Color {
+                        x: value,
+                        y: value,
+                        z: value,
+                    },
+                );
+            }
+        }
+        counts
+    }
+
+    // Copy of the pixels within `bounds`, accumulated sums and statistics
+    // included
+    pub fn crop(&self, bounds: &PixelBounds) -> Image {
+        self.copy_pixels(bounds.width(), bounds.height(), |x, y| {
+            Some((x + bounds.x0, y + bounds.y0))
+        })
+    }
+
+    // This image placed at `bounds` within an empty `width` by `height` one,
+    // the opposite of `crop`
+    pub fn pad(&self, bounds: &PixelBounds, width: usize, height: usize) -> Image {
+        self.copy_pixels(width, height, |x, y| {
+            bounds
+                .contains(x, y)
+                .then(|| (x - bounds.x0, y - bounds.y0))
+        })
+    }
+
+    // New image whose pixels come from `source` pixels of this one, or stay
+    // empty where there is none
+    fn copy_pixels(
+        &self,
+        width: usize,
+        height: usize,
+        source: impl Fn(usize, usize) -> Option<(usize, usize)>,
+    ) -> Image {
+        let mut image = Image::with_filter(width, height, self.filter);
+        for y in 0..height {
+            for x in 0..width {
+                let Some((source_x, source_y)) = source(x, y) else {
+                    continue;
+                };
+                let from = self.index(source_x, source_y);
+                let to = image.index(x, y);
+                image.pixels[to] = self.pixels[from];
+                image.weights[to] = self.weights[from];
+                image.samples[to] = self.samples[from];
+                image.luminance_mean[to] = self.luminance_mean[from];
+                image.luminance_m2[to] = self.luminance_m2[from];
+            }
+        }
+        image
+    }
+
+    // Filtered radiance of a pixel, black if nothing was rendered there
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        let index = self.index(x, y);
+        let weight = self.weights[index];
+        if weight.abs() < 1e-6 {
+            return Color::zero();
+        }
+        self.pixels[index] / weight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::*;
+
+    #[test]
+    fn test_crop_and_pad_round_trip() {
+        let mut image = Image::new(4, 3);
+        image.add_sample(
+            2,
+            1,
+            Color {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            },
+        );
+        image.add_sample(
+            0,
+            0,
+            Color {
+                x: 5.0,
+                y: 5.0,
+                z: 5.0,
+            },
+        );
+        let bounds = PixelBounds {
+            x0: 1,
+            y0: 1,
+            x1: 3,
+            y1: 3,
+        };
+
+        let cropped = image.crop(&bounds);
+        assert_eq!((cropped.width, cropped.height), (2, 2));
+        assert_eq!(
+            cropped.get(1, 0),
+            Color {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0
+            }
+        );
+        assert_eq!(cropped.samples, vec![0, 1, 0, 0]);
+
+        let padded = cropped.pad(&bounds, 4, 3);
+        assert_eq!(padded.get(2, 1), image.get(2, 1));
+        assert_eq!(padded.get(0, 0), Color::zero());
+    }
+
+    #[test]
+    fn test_box_filter_matches_pixel_average() {
+        let mut image = Image::new(2, 1);
+        image.splat(
+            0.25,
+            0.5,
+            Color {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        );
+        image.splat(
+            0.75,
+            0.5,
+            Color {
+                x: 3.0,
+                y: 3.0,
+                z: 3.0,
+            },
+        );
+        image.splat(
+            1.5,
+            0.5,
+            Color {
+                x: 5.0,
+                y: 5.0,
+                z: 5.0,
+            },
+        );
+        assert_eq!(
+            image.get(0, 0),
+            Color {
+                x: 2.0,
+                y: 2.0,
+                z: 2.0
+            }
+        );
+        assert_eq!(
+            image.get(1, 0),
+            Color {
+                x: 5.0,
+                y: 5.0,
+                z: 5.0
+            }
+        );
+        assert_eq!(image.samples, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_wide_filter_splats_into_neighbours() {
+        let mut image = Image::with_filter(3, 1, Filter::Tent { radius: 1.5 });
+        image.splat(
+            1.5,
+            0.5,
+            Color {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        );
+        assert!(image.weights[0] > 0.0);
+        assert!(image.weights[1] > image.weights[0]);
+        assert_eq!(image.weights[0], image.weights[2]);
+        assert_eq!(image.samples, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn test_running_variance() {
+        let mut image = Image::new(1, 1);
+        for value in [1.0, 2.0, 3.0, 4.0] {
+            image.add_sample(
+                0,
+                0,
+                Color {
+                    x: value,
+                    y: value,
+                    z: value,
+                },
+            );
+        }
+        assert!((image.luminance_mean[0] - 2.5).abs() < 1e-5);
+        assert!((image.variance(0, 0) - 5.0 / 3.0).abs() < 1e-5);
+        let expected = (5.0_f32 / 3.0 / 4.0).sqrt() / 2.5;
+        assert!((image.relative_error(0, 0) - expected).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,705 @@
+use std::sync::Arc;
+
+use crate::color::Color;
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::{transmittance, Medium};
+use crate::ray::Ray;
+use crate::sampler::*;
+use crate::spectrum::*;
+use crate::vector::*;
+
+// An integrator decides what color a camera ray ends up contributing to the
+// image. The path tracer is the "real" one, the rest are debug views that
+// are handy when something in a scene looks off.
+pub trait Integrator {
+    fn li(&self, ray: &Ray, world: &dyn Hittable, sampler: &mut dyn Sampler) -> Color;
+
+    // Radiance along with the features of the first visible surface, which
+    // guide the denoiser. Integrators that know better which surface is
+    // visible, like the path tracer skipping nested media, override this.
+    fn li_features(
+        &self,
+        ray: &Ray,
+        world: &dyn Hittable,
+        sampler: &mut dyn Sampler,
+    ) -> (Color, Features) {
+        let features = match world.hit(ray, 0.001, f32::INFINITY) {
+            Some(hit) => Features::from_hit(ray, &hit),
+            None => Features::miss(),
+        };
+        (self.li(ray, world, sampler), features)
+    }
+}
+
+// What the camera sees at the first hit, kept per pixel as AOVs and to
+// guide the denoiser. Only the path tracer splits up the lighting, the
+// radiance of a path is the sum of its `emission`, `direct` and `indirect`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    pub albedo: Color,
+    pub normal: Vec3,
+    // Distance from the camera
+    pub depth: f32,
+    pub position: Vec3,
+    // Both `None` for rays escaping to the sky
+    pub object_id: Option<u32>,
+    pub material: Option<usize>,
+    // Light given off by the first surface, or the sky seen directly
+    pub emission: Color,
+    // Light reaching the camera after a single bounce
+    pub direct: Color,
+    pub indirect: Color,
+}
+
+impl Features {
+    pub fn from_hit(ray: &Ray, hit: &HitRecord) -> Features {
+        Features {
+            albedo: hit.material.albedo(hit),
+            normal: hit.normal,
+            depth: (hit.point - ray.origin).length(),
+            position: hit.point,
+            object_id: Some(hit.object_id),
+            material: Some(material_key(hit)),
+            ..Features::miss()
+        }
+    }
+
+    // Rays escaping to the sky have no normal or depth, and a white albedo
+    // so that demodulating by it leaves the sky alone.
+    pub fn miss() -> Features {
+        Features {
+            albedo: Color {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            normal: Vec3::zero(),
+            depth: 0.0,
+            position: Vec3::zero(),
+            object_id: None,
+            material: None,
+            emission: Color::zero(),
+            direct: Color::zero(),
+            indirect: Color::zero(),
+        }
+    }
+
+    // Sort light found `bounce` surfaces into the path
+    fn add_light(&mut self, bounce: i32, light: Color) {
+        match bounce {
+            0 => self.emission += light,
+            1 => self.direct += light,
+            _ => self.indirect += light,
+        }
+    }
+
+    // Apply the camera's exposure to the lighting
+    pub fn scale_light(&mut self, scale: f32) {
+        self.emission = self.emission * scale;
+        self.direct = self.direct * scale;
+        self.indirect = self.indirect * scale;
+    }
+
+    pub fn radiance(&self) -> Color {
+        self.emission + self.direct + self.indirect
+    }
+}
+
+// Gradient from white to light blue, used as the environment for rays that
+// escape the scene.
+pub fn sky(ray: &Ray) -> Color {
+    let direction = unit_vector(&ray.direction);
+    let t = 0.5 * (direction.y + 1.0);
+    (1.0 - t)
+        * Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        }
+        + t * Color {
+            x: 0.5,
+            y: 0.7,
+            z: 1.0,
+        }
+}
+
+// Identifies a material instance, everything sharing the same `Arc` gets
+// the same key.
+pub fn material_key(hit: &HitRecord) -> usize {
+    Arc::as_ptr(&hit.material) as *const () as usize
+}
+
+// The media a path is currently inside of, for nested dielectrics
+#[derive(Default)]
+struct MediumStack {
+    entries: Vec<(usize, Medium)>,
+}
+
+impl MediumStack {
+    // The medium that wins wherever we are, ignoring `key` itself
+    fn dominant(&self, key: usize) -> Option<&Medium> {
+        self.entries
+            .iter()
+            .filter(|(entry, _)| *entry != key)
+            .map(|(_, medium)| medium)
+            .max_by_key(|medium| medium.priority)
+    }
+
+    // Attenuation over `distance` through whichever medium we are in
+    fn transmittance(&self, distance: f32) -> Color {
+        match self.dominant(usize::MAX) {
+            Some(medium) => transmittance(&medium.absorption, distance),
+            None => Color {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        }
+    }
+
+    fn enter(&mut self, key: usize, medium: Medium) {
+        self.entries.push((key, medium));
+    }
+
+    fn exit(&mut self, key: usize) {
+        if let Some(index) = self.entries.iter().position(|(entry, _)| *entry == key) {
+            self.entries.remove(index);
+        }
+    }
+
+    // Crossing the surface in `hit`, either into or out of its medium
+    fn cross(&mut self, hit: &HitRecord, medium: Medium) {
+        if hit.front_face {
+            self.enter(material_key(hit), medium);
+        } else {
+            self.exit(material_key(hit));
+        }
+    }
+}
+
+// Where a ray ends up past the surfaces that don't count, with the
+// absorption along the way
+enum Intersection {
+    Hit(HitRecord, Color),
+    Escaped(Color),
+    // Too many surfaces to skip, the path is cut off and adds nothing
+    Terminated,
+}
+
+// With `spectral` set every path carries a single wavelength, which lets
+// dispersive materials split light. RGB albedos and the sky are upsampled
+// to spectra along the way and the result is converted back to RGB.
+pub struct PathTracer {
+    pub max_depth: i32,
+    pub spectral: bool,
+}
+
+impl PathTracer {
+    // Surfaces of lower priority media inside a higher priority one don't
+    // exist as far as the light is concerned, skip past them but keep track
+    // of having crossed them.
+    const MAX_SKIPPED_SURFACES: u32 = 32;
+
+    // Also returns the absorption along the way. Past the limit the path is
+    // cut off rather than taken to escape to the sky from inside the scene.
+    fn intersect(
+        &self,
+        ray: &mut Ray,
+        world: &dyn Hittable,
+        media: &mut MediumStack,
+    ) -> Intersection {
+        let mut attenuation = Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        for _ in 0..PathTracer::MAX_SKIPPED_SURFACES {
+            let Some(mut hit) = world.hit(ray, 0.001, f32::INFINITY) else {
+                return Intersection::Escaped(attenuation);
+            };
+            attenuation *= media.transmittance(hit.t * ray.direction.length());
+            let Some(medium) = hit.material.medium(ray.wavelength) else {
+                return Intersection::Hit(hit, attenuation);
+            };
+            let key = material_key(&hit);
+            match media.dominant(key) {
+                Some(dominant) if dominant.priority > medium.priority => {
+                    media.cross(&hit, medium);
+                    ray.origin = hit.point;
+                }
+                dominant => {
+                    hit.exterior_ior = dominant.map_or(1.0, |dominant| dominant.ior);
+                    return Intersection::Hit(hit, attenuation);
+                }
+            }
+        }
+        Intersection::Terminated
+    }
+
+    // Scatter off `hit`, updating the media when the light refracted through
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit: &HitRecord,
+        media: &mut MediumStack,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let (attenuation, scattered) = hit.material.scatter(ray, hit, sampler)?;
+        if let Some(medium) = hit.material.medium(ray.wavelength) {
+            if dot(&scattered.direction, &hit.normal) < 0.0 {
+                media.cross(hit, medium);
+            }
+        }
+        Some((attenuation, scattered))
+    }
+
+    fn li_rgb(
+        &self,
+        primary: &Ray,
+        world: &dyn Hittable,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+    ) -> Color {
+        let mut throughput = Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let mut ray = *primary;
+        let mut media = MediumStack::default();
+        for depth in 0..self.max_depth {
+            sampler.set_dimension(bounce_dimension(depth as u32));
+            let (hit, absorption) = match self.intersect(&mut ray, world, &mut media) {
+                Intersection::Hit(hit, absorption) => (hit, absorption),
+                Intersection::Escaped(absorption) => {
+                    features.add_light(depth, throughput * absorption * sky(&ray));
+                    break;
+                }
+                Intersection::Terminated => break,
+            };
+            if depth == 0 {
+                *features = Features::from_hit(primary, &hit);
+            }
+            throughput *= absorption;
+            features.add_light(depth, throughput * hit.material.emitted(&hit));
+            let Some((attenuation, scattered)) = self.scatter(&ray, &hit, &mut media, sampler)
+            else {
+                break;
+            };
+            throughput *= attenuation;
+            ray = scattered;
+        }
+        features.radiance()
+    }
+
+    fn li_spectral(
+        &self,
+        primary: &Ray,
+        world: &dyn Hittable,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+    ) -> Color {
+        // A camera tracing its lens spectrally has already picked one
+        let wavelength = primary.wavelength.unwrap_or_else(|| {
+            sampler.set_dimension(WAVELENGTH_DIMENSION);
+            sample_wavelength(sampler.get_1d())
+        });
+        let mut throughput = 1.0;
+        let mut ray = Ray {
+            wavelength: Some(wavelength),
+            ..*primary
+        };
+        let mut media = MediumStack::default();
+        for depth in 0..self.max_depth {
+            sampler.set_dimension(bounce_dimension(depth as u32));
+            let (hit, absorption) = match self.intersect(&mut ray, world, &mut media) {
+                Intersection::Hit(hit, absorption) => (hit, absorption),
+                Intersection::Escaped(absorption) => {
+                    let radiance =
+                        throughput * rgb_to_spectrum(&(absorption * sky(&ray)), wavelength);
+                    features.add_light(depth, spectral_to_rgb(radiance, wavelength));
+                    break;
+                }
+                Intersection::Terminated => break,
+            };
+            if depth == 0 {
+                *features = Features::from_hit(primary, &hit);
+            }
+            throughput *= rgb_to_spectrum(&absorption, wavelength);
+            let emitted = throughput * rgb_to_spectrum(&hit.material.emitted(&hit), wavelength);
+            features.add_light(depth, spectral_to_rgb(emitted, wavelength));
+            let Some((attenuation, scattered)) = self.scatter(&ray, &hit, &mut media, sampler)
+            else {
+                break;
+            };
+            throughput *= rgb_to_spectrum(&attenuation, wavelength);
+            ray = scattered;
+        }
+        features.radiance()
+    }
+}
+
+impl Integrator for PathTracer {
+    fn li(&self, ray: &Ray, world: &dyn Hittable, sampler: &mut dyn Sampler) -> Color {
+        self.li_features(ray, world, sampler).0
+    }
+
+    fn li_features(
+        &self,
+        ray: &Ray,
+        world: &dyn Hittable,
+        sampler: &mut dyn Sampler,
+    ) -> (Color, Features) {
+        let mut features = Features::miss();
+        let color = if self.spectral {
+            self.li_spectral(ray, world, sampler, &mut features)
+        } else {
+            self.li_rgb(ray, world, sampler, &mut features)
+        };
+        (color, features)
+    }
+}
+
+// Shading normal remapped from [-1, 1] to [0, 1]
+pub struct Normals;
+
+impl Integrator for Normals {
+    fn li(&self, ray: &Ray, world: &dyn Hittable, _sampler: &mut dyn Sampler) -> Color {
+        match world.hit(ray, 0.001, f32::INFINITY) {
+            Some(hit) => 0.5 * (hit.normal + 1.0),
+            None => Color::zero(),
+        }
+    }
+}
+
+// Hit distance along the ray, white up close fading to black at
+// `max_distance`. Misses are black.
+pub struct Depth {
+    pub max_distance: f32,
+}
+
+impl Integrator for Depth {
+    fn li(&self, ray: &Ray, world: &dyn Hittable, _sampler: &mut dyn Sampler) -> Color {
+        match world.hit(ray, 0.001, f32::INFINITY) {
+            Some(hit) => {
+                let distance = hit.t * ray.direction.length();
+                let value = 1.0 - (distance / self.max_distance).clamp(0.0, 1.0);
+                Color {
+                    x: value,
+                    y: value,
+                    z: value,
+                }
+            }
+            None => Color::zero(),
+        }
+    }
+}
+
+// Surface color of the first hit, without any lighting
+pub struct Albedo;
+
+impl Integrator for Albedo {
+    fn li(&self, ray: &Ray, world: &dyn Hittable, _sampler: &mut dyn Sampler) -> Color {
+        match world.hit(ray, 0.001, f32::INFINITY) {
+            Some(hit) => hit.material.albedo(&hit),
+            None => Color::zero(),
+        }
+    }
+}
+
+// Every material instance gets a stable but arbitrary color, so it is easy to
+// see which surfaces share a material.
+pub struct MaterialId;
+
+impl Integrator for MaterialId {
+    fn li(&self, ray: &Ray, world: &dyn Hittable, _sampler: &mut dyn Sampler) -> Color {
+        match world.hit(ray, 0.001, f32::INFINITY) {
+            Some(hit) => false_color(material_key(&hit) as u64),
+            None => Color::zero(),
+        }
+    }
+}
+
+// Fraction of cosine weighted rays around the normal that don't hit anything
+// within `distance`.
+pub struct AmbientOcclusion {
+    pub samples: u32,
+    pub distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, ray: &Ray, world: &dyn Hittable, sampler: &mut dyn Sampler) -> Color {
+        let Some(hit) = world.hit(ray, 0.001, f32::INFINITY) else {
+            return Color::zero();
+        };
+        let mut unoccluded = 0;
+        sampler.set_dimension(bounce_dimension(0));
+        for _ in 0..self.samples {
+            let mut direction = hit.normal + sample_unit_vector(sampler.get_2d());
+            if direction.near_zero() {
+                direction = hit.normal;
+            }
+            let probe = Ray {
+                origin: hit.point,
+                direction: unit_vector(&direction),
+                wavelength: None,
+                time: ray.time,
+            };
+            if world.hit(&probe, 0.001, self.distance).is_none() {
+                unoccluded += 1;
+            }
+        }
+        let value = unoccluded as f32 / self.samples.max(1) as f32;
+        Color {
+            x: value,
+            y: value,
+            z: value,
+        }
+    }
+}
+
+// Scramble the bits of `key` and use them as a color, nearby keys end up
+// with very different colors.
+pub fn false_color(key: u64) -> Color {
+    let mut h = key;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    Color {
+        x: (h & 0xff) as f32 / 255.0,
+        y: ((h >> 8) & 0xff) as f32 / 255.0,
+        z: ((h >> 16) & 0xff) as f32 / 255.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hittable_list::HittableList;
+    use crate::integrator::*;
+    use crate::material::{Dielectric, Lambertian};
+    use crate::sphere::Sphere;
+
+    // A ray straight into an index matched sphere of high priority, with
+    // `shells` spheres of lower priority nested inside it
+    fn nested_media(shells: usize) -> Color {
+        let mut world = HittableList { objects: vec![] };
+        let outer = Dielectric {
+            priority: 2,
+            ..Dielectric::new(1.0)
+        };
+        world.add(Box::new(Sphere::new(Vec3::zero(), 10.0, Arc::new(outer))));
+        for i in 0..shells {
+            let inner = Dielectric {
+                priority: 1,
+                ..Dielectric::new(1.5)
+            };
+            world.add(Box::new(Sphere::new(
+                Vec3::zero(),
+                1.0 + 0.1 * i as f32,
+                Arc::new(inner),
+            )));
+        }
+        let ray = Ray {
+            origin: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 20.0,
+            },
+            direction: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            wavelength: None,
+            time: 0.0,
+        };
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+        sampler.start_pixel_sample(0, 0, 0);
+        PathTracer {
+            max_depth: 8,
+            spectral: false,
+        }
+        .li(&ray, &world, sampler.as_mut())
+    }
+
+    #[test]
+    fn test_debug_views() {
+        // A sphere two units ahead, seen head on
+        let albedo = Color {
+            x: 0.2,
+            y: 0.4,
+            z: 0.6,
+        };
+        let mut world = HittableList { objects: vec![] };
+        world.add(Box::new(Sphere::new(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -3.0,
+            },
+            1.0,
+            Arc::new(Lambertian { albedo }),
+        )));
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            wavelength: None,
+            time: 0.0,
+        };
+        let miss = Ray {
+            direction: Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            ..ray
+        };
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+        sampler.start_pixel_sample(0, 0, 0);
+
+        let gray = |value| Color {
+            x: value,
+            y: value,
+            z: value,
+        };
+        let ao = AmbientOcclusion {
+            samples: 16,
+            distance: 10.0,
+        };
+        let views: [(&dyn Integrator, Color); 4] = [
+            (
+                &Normals,
+                Color {
+                    x: 0.5,
+                    y: 0.5,
+                    z: 1.0,
+                },
+            ),
+            (&Depth { max_distance: 4.0 }, gray(0.5)),
+            (&Albedo, albedo),
+            // Nothing but open sky in front of a lone sphere
+            (&ao, gray(1.0)),
+        ];
+        for (view, expected) in views {
+            assert!((view.li(&ray, &world, sampler.as_mut()) - expected).length() < 1e-5);
+            assert_eq!(view.li(&miss, &world, sampler.as_mut()), Color::zero());
+        }
+
+        let id = MaterialId.li(&ray, &world, sampler.as_mut());
+        assert_ne!(id, Color::zero());
+        assert_eq!(
+            MaterialId.li(
+                &Ray {
+                    direction: Vec3 {
+                        x: 0.1,
+                        y: 0.0,
+                        z: -1.0
+                    },
+                    ..ray
+                },
+                &world,
+                sampler.as_mut()
+            ),
+            id
+        );
+    }
+
+    #[test]
+    fn test_nested_media_interface() {
+        // Water overlapping the bottom of a glass ball, the glass winning
+        // where they overlap
+        let glass = Arc::new(Dielectric {
+            priority: 2,
+            ..Dielectric::new(1.5)
+        });
+        let water = Arc::new(Dielectric {
+            priority: 1,
+            ..Dielectric::new(1.33)
+        });
+        let mut world = HittableList { objects: vec![] };
+        world.add(Box::new(Sphere::new(Vec3::zero(), 1.0, glass)));
+        world.add(Box::new(Sphere::new(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.5,
+            },
+            1.0,
+            water,
+        )));
+        let tracer = PathTracer {
+            max_depth: 8,
+            spectral: false,
+        };
+        let mut media = MediumStack::default();
+
+        let mut ray = Ray {
+            origin: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 5.0,
+            },
+            direction: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            wavelength: None,
+            time: 0.0,
+        };
+        let Intersection::Hit(hit, _) = tracer.intersect(&mut ray, &world, &mut media) else {
+            panic!("missed the glass");
+        };
+        assert!((hit.point.z - 1.0).abs() < 1e-4);
+        assert_eq!(hit.exterior_ior, 1.0);
+        media.cross(&hit, hit.material.medium(None).unwrap());
+        ray.origin = hit.point;
+
+        // The water's surface inside the glass doesn't exist, the next hit
+        // is the glass leaving into water
+        let Intersection::Hit(hit, _) = tracer.intersect(&mut ray, &world, &mut media) else {
+            panic!("missed the glass");
+        };
+        assert!((hit.point.z + 1.0).abs() < 1e-4);
+        assert!(!hit.front_face);
+        assert_eq!(hit.exterior_ior, 1.33);
+
+        // So light refracts from glass into water, not into air
+        let oblique = Ray {
+            origin: Vec3 {
+                x: -0.3,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: hit.point
+                - Vec3 {
+                    x: -0.3,
+                    y: 0.0,
+                    z: 0.0,
+                },
+            ..ray
+        };
+        let expected = refract(&unit_vector(&oblique.direction), &hit.normal, 1.5 / 1.33);
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+        let refracted = (0..64).find_map(|index| {
+            sampler.start_pixel_sample(0, 0, index);
+            let (_, scattered) = hit.material.scatter(&oblique, &hit, sampler.as_mut())?;
+            (dot(&scattered.direction, &hit.normal) < 0.0).then_some(scattered.direction)
+        });
+        assert!((refracted.unwrap() - expected).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_nested_media_skip_limit() {
+        // Skipping a few surfaces the ray goes straight through to the sky
+        assert!(nested_media(4).y > 0.0);
+        // With more than can be skipped the path ends black, not in the sky
+        assert_eq!(nested_media(20), Color::zero());
+    }
+}
@@ -0,0 +1,433 @@
+use std::f32::consts::PI;
+
+use crate::material::Ior;
+use crate::ray::Ray;
+use crate::vector::*;
+
+// One surface of a lens prescription, in millimeters. Elements are listed
+// from the front of the lens to the film, the way patents and lens design
+// books tabulate them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensElement {
+    // Radius of curvature, positive when the surface bulges towards the
+    // scene. Zero marks the aperture stop.
+    pub radius: f32,
+    // Distance along the axis to the next surface, or to the film for the
+    // last one
+    pub thickness: f32,
+    // Medium between this surface and the next
+    pub ior: Ior,
+    // Diameter of the clear aperture
+    pub aperture: f32,
+}
+
+// A real lens, traced surface by surface instead of approximated as a thin
+// lens. Vignetting, distortion, cat's eye bokeh and, when rendering
+// spectrally, chromatic aberration all fall out of the tracing.
+// https://pbr-book.org/3ed-2018/Camera_Models/Realistic_Cameras
+//
+// Lens space has the film at z = 0 and the lens in front of it along -z,
+// with the scene beyond.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LensSystem {
+    pub elements: Vec<LensElement>,
+    // Number of aperture blades, giving polygonal bokeh. Zero for a round
+    // aperture.
+    pub blades: u32,
+    // Width of the film in millimeters, 36 for full frame
+    pub film_width: f32,
+}
+
+// Where a ray meets the spherical surface centered on the axis at
+// `center_z`, on the side the lens uses, and the normal facing the ray
+fn intersect_surface(ray: &Ray, radius: f32, center_z: f32) -> Option<(f32, Vec3)> {
+    let center = Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: center_z,
+    };
+    let oc = ray.origin - center;
+    let a = ray.direction.length_squared();
+    let half_b = dot(&oc, &ray.direction);
+    let c = oc.length_squared() - radius * radius;
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let closer = (ray.direction.z > 0.0) ^ (radius < 0.0);
+    let t = if closer {
+        (-half_b - root) / a
+    } else {
+        (-half_b + root) / a
+    };
+    if t < 0.0 {
+        return None;
+    }
+    let mut normal = unit_vector(&(ray.at(t) - center));
+    if dot(&normal, &ray.direction) > 0.0 {
+        normal = -normal;
+    }
+    Some((t, normal))
+}
+
+// Snell's law through an interface facing `normal`, or None on total
+// internal reflection
+fn refract_through(direction: &Vec3, normal: &Vec3, eta_ratio: f32) -> Option<Vec3> {
+    let direction = unit_vector(direction);
+    let cos_theta = dot(&-direction, normal).min(1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    if eta_ratio * sin_theta > 1.0 {
+        return None;
+    }
+    Some(refract(&direction, normal, eta_ratio))
+}
+
+impl LensSystem {
+    pub fn new(elements: Vec<LensElement>) -> LensSystem {
+        LensSystem {
+            elements,
+            blades: 0,
+            film_width: 36.0,
+        }
+    }
+
+    // F/2 double Gauss scaled to a 50mm focal length, from US patent
+    // 2,673,491 via Modern Lens Design. The patent only gives indices, the
+    // Abbe numbers are those of common glasses with matching indices.
+    pub fn double_gauss() -> LensSystem {
+        let element = |radius, thickness, ior, abbe, aperture| LensElement {
+            radius,
+            thickness,
+            ior: if ior == 1.0 {
+                Ior::Constant(1.0)
+            } else {
+                Ior::abbe(ior, abbe)
+            },
+            aperture,
+        };
+        let mut lens = LensSystem::new(vec![
+            element(29.475, 3.76, 1.67, 47.2, 25.2),
+            element(84.83, 0.12, 1.0, 0.0, 25.2),
+            element(19.275, 4.025, 1.67, 47.2, 23.0),
+            element(40.77, 3.275, 1.699, 30.1, 23.0),
+            element(12.75, 5.705, 1.0, 0.0, 18.0),
+            element(0.0, 4.5, 1.0, 0.0, 17.1),
+            element(-14.495, 1.18, 1.603, 38.0, 17.0),
+            element(40.77, 6.065, 1.658, 57.3, 20.0),
+            element(-20.385, 0.19, 1.0, 0.0, 20.0),
+            element(437.065, 3.22, 1.717, 48.0, 20.0),
+            element(-39.73, 0.0, 1.0, 0.0, 20.0),
+        ]);
+        lens.focus(f32::INFINITY)
+            .expect("the double Gauss focuses at infinity");
+        lens
+    }
+
+    // Reads a prescription with one surface per line: radius, thickness,
+    // index of refraction and aperture diameter, optionally followed by
+    // the Abbe number for dispersion. Zero or one for the index is air.
+    // Lines starting with # are comments. The lens is focused at infinity,
+    // which fails when light can't get through it along the axis.
+    pub fn parse(text: &str) -> Result<LensSystem, String> {
+        let mut elements = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(|value| value.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|error| format!("line {}: {error}", number + 1))?;
+            let ior = match values[..] {
+                [_, _, n, _, ..] if n == 0.0 || n == 1.0 => Ior::Constant(1.0),
+                [_, _, n, _] => Ior::Constant(n),
+                [_, _, n, _, abbe] => Ior::abbe(n, abbe),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected 4 or 5 values, got {}",
+                        number + 1,
+                        values.len()
+                    ))
+                }
+            };
+            elements.push(LensElement {
+                radius: values[0],
+                thickness: values[1],
+                ior,
+                aperture: values[3],
+            });
+        }
+        let mut lens = LensSystem::new(elements);
+        lens.focus(f32::INFINITY)?;
+        Ok(lens)
+    }
+
+    // z of the rear surface, which the film looks at
+    pub fn rear_z(&self) -> f32 {
+        -self.elements.last().unwrap().thickness
+    }
+
+    pub fn rear_radius(&self) -> f32 {
+        self.elements.last().unwrap().aperture / 2.0
+    }
+
+    fn front_z(&self) -> f32 {
+        -self
+            .elements
+            .iter()
+            .map(|element| element.thickness)
+            .sum::<f32>()
+    }
+
+    fn inside_aperture(&self, element: &LensElement, point: &Vec3) -> bool {
+        let radius = element.aperture / 2.0;
+        let r2 = point.x * point.x + point.y * point.y;
+        if element.radius != 0.0 || self.blades < 3 {
+            return r2 <= radius * radius;
+        }
+        // Regular polygon inscribed in the stop, one blade edge at the
+        // bottom. `angle` is from the middle of the nearest edge.
+        let sector = 2.0 * PI / self.blades as f32;
+        let angle =
+            (point.y.atan2(point.x) + PI / 2.0 + sector / 2.0).rem_euclid(sector) - sector / 2.0;
+        r2.sqrt() * angle.cos() <= radius * (sector / 2.0).cos()
+    }
+
+    // Traces a ray leaving the film through every surface out into the
+    // scene, None when something blocks it
+    pub fn trace_from_film(&self, ray: &Ray, wavelength: Option<f32>) -> Option<Ray> {
+        let mut ray = *ray;
+        let mut element_z = 0.0;
+        for i in (0..self.elements.len()).rev() {
+            let element = &self.elements[i];
+            element_z -= element.thickness;
+            if !self.trace_surface(&mut ray, i, element_z, wavelength) {
+                return None;
+            }
+        }
+        Some(ray)
+    }
+
+    // Traces a ray from the scene through every surface to the film side
+    pub fn trace_from_scene(&self, ray: &Ray, wavelength: Option<f32>) -> Option<Ray> {
+        let mut ray = *ray;
+        let mut element_z = self.front_z();
+        for (i, element) in self.elements.iter().enumerate() {
+            if !self.trace_surface(&mut ray, i, element_z, wavelength) {
+                return None;
+            }
+            element_z += element.thickness;
+        }
+        Some(ray)
+    }
+
+    // Moves `ray` onto surface `i` at `element_z` and bends it into the
+    // medium on the other side
+    fn trace_surface(
+        &self,
+        ray: &mut Ray,
+        i: usize,
+        element_z: f32,
+        wavelength: Option<f32>,
+    ) -> bool {
+        let element = &self.elements[i];
+        if element.radius == 0.0 {
+            let t = (element_z - ray.origin.z) / ray.direction.z;
+            if t < 0.0 {
+                return false;
+            }
+            ray.origin = ray.at(t);
+            return self.inside_aperture(element, &ray.origin);
+        }
+
+        let Some((t, normal)) = intersect_surface(ray, element.radius, element_z + element.radius)
+        else {
+            return false;
+        };
+        let point = ray.at(t);
+        if !self.inside_aperture(element, &point) {
+            return false;
+        }
+
+        // Element i's medium is on the film side of its surface
+        let film_side = element.ior.at(wavelength);
+        let scene_side = if i > 0 {
+            self.elements[i - 1].ior.at(wavelength)
+        } else {
+            1.0
+        };
+        let eta_ratio = if ray.direction.z < 0.0 {
+            film_side / scene_side
+        } else {
+            scene_side / film_side
+        };
+        let Some(direction) = refract_through(&ray.direction, &normal, eta_ratio) else {
+            return false;
+        };
+        ray.origin = point;
+        ray.direction = direction;
+        true
+    }
+
+    // Focal point and principal plane z from a ray entering parallel to
+    // the axis and where it leaves the lens
+    fn cardinal_points(entering: &Ray, leaving: &Ray) -> (f32, f32) {
+        let focal = leaving.at(-leaving.origin.x / leaving.direction.x).z;
+        let principal = leaving
+            .at((entering.origin.x - leaving.origin.x) / leaving.direction.x)
+            .z;
+        (focal, principal)
+    }
+
+    // Thick lens approximation: principal planes and focal points on the
+    // film side and on the scene side. None when a ray close to the axis is
+    // blocked, or comes out parallel to it.
+    fn thick_lens(&self) -> Option<[(f32, f32); 2]> {
+        if self.elements.is_empty() {
+            return None;
+        }
+        let height = 0.001 * self.film_width;
+        let axis = |z: f32| Vec3 { x: 0.0, y: 0.0, z };
+        let from_scene = Ray {
+            origin: Vec3 {
+                x: height,
+                y: 0.0,
+                z: self.front_z() - 1.0,
+            },
+            direction: axis(1.0),
+            wavelength: None,
+            time: 0.0,
+        };
+        let from_film = Ray {
+            origin: Vec3 {
+                x: height,
+                y: 0.0,
+                z: self.rear_z() + 1.0,
+            },
+            direction: axis(-1.0),
+            wavelength: None,
+            time: 0.0,
+        };
+        let film_side = self.trace_from_scene(&from_scene, None)?;
+        let scene_side = self.trace_from_film(&from_film, None)?;
+        let points = [
+            LensSystem::cardinal_points(&from_scene, &film_side),
+            LensSystem::cardinal_points(&from_film, &scene_side),
+        ];
+        points
+            .iter()
+            .all(|(focal, principal)| focal.is_finite() && principal.is_finite())
+            .then_some(points)
+    }
+
+    // Effective focal length in millimeters
+    pub fn focal_length(&self) -> Option<f32> {
+        let [(focal, principal), _] = self.thick_lens()?;
+        Some(focal - principal)
+    }
+
+    // Moves the lens so that things `distance` millimeters in front of the
+    // film are sharp. A lens can't focus closer than four focal lengths
+    // from the film, object to image.
+    pub fn focus(&mut self, distance: f32) -> Result<(), String> {
+        if self.elements.is_empty() {
+            return Err("no lens elements".to_string());
+        }
+        let [(focal, film_principal), (_, scene_principal)] = self
+            .thick_lens()
+            .ok_or_else(|| "no light gets through the lens along its axis".to_string())?;
+        let f = focal - film_principal;
+        // Moving the lens forward by delta gives the object and image
+        // distances a - delta and b + delta from the principal planes
+        let delta = if distance.is_finite() {
+            let a = scene_principal + distance;
+            let b = -film_principal;
+            let discriminant = (a + b) * (a + b - 4.0 * f);
+            if discriminant < 0.0 {
+                return Err(format!(
+                    "can't focus at {distance}mm, closer than 4 times the {f}mm focal length"
+                ));
+            }
+            ((a - b) - discriminant.sqrt()) / 2.0
+        } else {
+            f + film_principal
+        };
+        self.elements.last_mut().unwrap().thickness += delta;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lens::*;
+
+    #[test]
+    fn test_double_gauss_focuses() {
+        let mut lens = LensSystem::double_gauss();
+        assert!((lens.focal_length().unwrap() - 50.0).abs() < 1.0);
+        assert!(lens.clone().focus(150.0).is_err());
+
+        // Rays from the middle of the film through anywhere on the rear
+        // element all meet again at the focus distance
+        lens.focus(1000.0).unwrap();
+        let mut through = 0;
+        for (x, y) in [(0.3, 0.0), (0.0, -0.5), (-0.4, 0.4), (0.1, 0.1)] {
+            let target = Vec3 {
+                x: x * lens.rear_radius(),
+                y: y * lens.rear_radius(),
+                z: lens.rear_z(),
+            };
+            let ray = Ray {
+                origin: Vec3::zero(),
+                direction: target,
+                wavelength: None,
+                time: 0.0,
+            };
+            let Some(out) = lens.trace_from_film(&ray, None) else {
+                continue;
+            };
+            through += 1;
+            let at_focus = out.at((-1000.0 - out.origin.z) / out.direction.z);
+            assert!(
+                (at_focus.x * at_focus.x + at_focus.y * at_focus.y).sqrt() < 0.5,
+                "{at_focus:?}"
+            );
+        }
+        // Vignetting may block one near the edge, but not most of them
+        assert!(through >= 3, "only {through} rays got through");
+
+        assert!(LensSystem::new(vec![]).focus(f32::INFINITY).is_err());
+        assert!(LensSystem::parse("# nothing\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_and_blades() {
+        let text = "# radius thickness ior aperture\n50 5 1.5 20 64\n0 5 1 10\n-50 40 1 20\n";
+        let mut lens = LensSystem::parse(text).unwrap();
+        assert_eq!(lens.elements.len(), 3);
+        assert_eq!(lens.elements[0].ior, Ior::abbe(1.5, 64.0));
+        assert!(LensSystem::parse("50 5 1.5").is_err());
+
+        // Air has no Abbe number, whatever the column says
+        let air = LensSystem::parse("50 5 1.5 20 64\n0 5 1 10 0\n-50 40 0 20 0\n").unwrap();
+        assert_eq!(air.elements[1].ior, Ior::Constant(1.0));
+        assert_eq!(air.elements[2].ior, Ior::Constant(1.0));
+
+        // A closed stop lets nothing through
+        assert!(LensSystem::parse("50 5 1.5 20\n0 5 1 0\n-50 40 1 20\n").is_err());
+
+        // A hexagonal stop cuts off the round stop's edge between corners
+        let stop = lens.elements[1];
+        let edge = Vec3 {
+            x: 0.0,
+            y: -4.8,
+            z: 0.0,
+        };
+        assert!(lens.inside_aperture(&stop, &edge));
+        lens.blades = 6;
+        assert!(!lens.inside_aperture(&stop, &edge));
+    }
+}
@@ -1,8 +1,23 @@
+pub mod aov;
 pub mod camera;
+pub mod checkpoint;
 pub mod color;
+pub mod denoise;
+pub mod encoder;
+pub mod filter;
 pub mod hittable;
 pub mod hittable_list;
+pub mod image;
+pub mod integrator;
+pub mod lens;
+pub mod material;
+pub mod microfacet;
+pub mod moving;
 pub mod ray;
+pub mod render;
+pub mod sampler;
+pub mod spectrum;
 pub mod sphere;
+pub mod texture;
+pub mod thin_film;
 pub mod vector;
-pub mod material;
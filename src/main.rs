@@ -1,67 +1,575 @@
 // https://raytracing.github.io/books/RayTracingInOneWeekend.html
 // To render an image, run
 // cargo run > image.ppm
+//
+// Debug views are available through `--integrator`, see
+// `integrator_from_args` for the list. Pass `--spectral` to trace
+// wavelengths instead of RGB, needed to see dispersion. `--format pfm`
+// writes linear floats instead of a display ready PPM. `--filter` picks the
+// pixel reconstruction filter and `--sampler` the sample generator, with
+// `--seed` to get a different but reproducible noise pattern.
+// `--adaptive 0.01` keeps sampling each pixel until its relative error is
+// below 1%, between `--min-spp` and `--max-spp` samples, and
+// `--sample-counts counts.ppm` saves how many samples each pixel took.
+// `--denoise` runs the image through the feature guided denoiser, handy
+// with a low `--spp` for previews.
+// `--aovs normal,depth,...` (or `all`) also writes AOVs, as layers of the
+// same file with `--format exr` or else as separate `aov_<name>` images.
+// PPM output goes through `--exposure` (in stops), `--tonemap` and,
+// with `--dither`, ordered dithering before being quantized to 8 bits, for
+// the `--display` color space (srgb, p3, rec2020 or acescg).
+// Giving any of `--iso`, `--shutter` (seconds) or `--f-number` switches to a
+// physical camera, whose exposure expects lights in cd/m^2. The sky is only
+// about 1 cd/m^2, e.g. `--iso 100 --shutter 1.2 --f-number 1` shows it as is.
+// `--motion x,y,z` moves the blue sphere at that velocity, in units per
+// second, blurring it over the time the shutter is open, a second unless
+// the physical camera's `--shutter` says otherwise.
+// `--projection` swaps the perspective view for orthographic, fisheye
+// (with `--fov` in degrees), equirectangular or cube map, the last two
+// changing the image to 2:1 and 6:1 for environment probes.
+// `--stereo side-by-side|top-bottom` renders both eyes into one image,
+// `--interocular` apart and converging at `--convergence`. With the
+// equirectangular projection that gives omnidirectional stereo for VR.
+// `--lens double-gauss` or `--lens prescription.txt` traces a real lens
+// instead of the thin lens (see `LensSystem::parse` for the format), with
+// `--blades` for a polygonal aperture. With `--spectral` it also shows
+// chromatic aberration.
+// `--resolution 1920x1080` changes the image size, per eye for stereo.
+// `--intrinsics fx,fy,cx,cy[,skew]` with `--extrinsic` (a camera to world
+// matrix, 12 or 16 comma separated values, row by row) and `--distortion
+// k1,k2,p1,p2[,k3]` renders through a calibrated camera, in OpenCV's
+// conventions. The intrinsics are taken to be for the rendered size unless
+// `--calibration-resolution` gives the size they were calibrated at, e.g.
+// to preview a 1920x1080 calibration at 480x270. The two need the same
+// aspect ratio.
+// `--crop x0,y0,x1,y1` (pixels) or `--crop-window` (fractions of the frame)
+// only renders that region and writes it on its own, or with `--pad` in
+// place in the full frame, as the data window of an EXR.
+// `--pass-spp 4` renders progressively, that many samples per pixel per
+// pass. `--checkpoint render.ckpt` saves progress every
+// `--checkpoint-interval` seconds (60 by default), passing one sample at a
+// time unless told otherwise, and `--resume render.ckpt` carries on from
+// such a file with the same arguments, giving the same image as an
+// uninterrupted render.
+// `--time-budget 300` (seconds) or `--target-noise 0.02` (relative error)
+// render progressively until the time is up or the image is that clean,
+// `--spp` being an upper limit then. `--report report.json` saves the
+// samples per pixel and noise achieved.
+//
+// `renderer merge a.ckpt b.ckpt ... > merged.ppm` combines checkpoints of
+// the same scene rendered with different seeds, e.g. on several machines,
+// into one image with the samples of all of them. It takes `--format`,
+// the output transform flags and `--sample-counts` like a render, and
+// `--save merged.ckpt` to keep the merged buffers.
 
-use rand::Rng;
-use std::io::prelude::*;
+use std::fs::File;
 use std::io::BufWriter;
-use std::rc::Rc;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-use renderer::camera::Camera;
+use renderer::aov::*;
+use renderer::camera::*;
+use renderer::checkpoint::{merge, Checkpoint};
+use renderer::color::space::ColorSpace;
+use renderer::color::tonemap::*;
 use renderer::color::*;
-use renderer::hittable::*;
+use renderer::denoise::Denoiser;
+use renderer::encoder::*;
+use renderer::filter::Filter;
 use renderer::hittable_list::*;
-use renderer::ray::*;
-use renderer::sphere::*;
-use renderer::vector::*;
+use renderer::image::{Image, PixelBounds};
+use renderer::integrator::*;
+use renderer::lens::LensSystem;
 use renderer::material::*;
+use renderer::moving::Moving;
+use renderer::render::*;
+use renderer::sampler::{hash, SamplerKind};
+use renderer::sphere::*;
+
+// Value following `flag` on the command line, as in `--flag value`
+fn arg_value(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+        .cloned()
+}
+
+fn has_flag(flag: &str) -> bool {
+    std::env::args().any(|arg| arg == flag)
+}
+
+// Pick the integrator from the command line, defaulting to the path tracer,
+// e.g. `cargo run -- --integrator normals > normals.ppm`
+fn integrator_from_args(max_depth: i32) -> Box<dyn Integrator> {
+    let name = arg_value("--integrator").unwrap_or_else(|| "path".to_string());
+
+    // Spectral rendering only makes a difference to the path tracer
+    let spectral = has_flag("--spectral");
+
+    match name.as_str() {
+        "path" => Box::new(PathTracer {
+            max_depth,
+            spectral,
+        }),
+        "normals" => Box::new(Normals),
+        "depth" => Box::new(Depth { max_distance: 5.0 }),
+        "albedo" => Box::new(Albedo),
+        "material-id" => Box::new(MaterialId),
+        "ao" => Box::new(AmbientOcclusion {
+            samples: 16,
+            distance: 1.0,
+        }),
+        _ => {
+            eprintln!(
+                "Unknown integrator '{name}', expected one of: path, normals, depth, albedo, material-id, ao"
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// Output encoding, `--format pfm` keeps the linear HDR values
+fn format_from_args() -> Format {
+    let name = arg_value("--format").unwrap_or_else(|| "ppm".to_string());
+
+    Format::from_name(&name).unwrap_or_else(|| {
+        eprintln!("Unknown format '{name}', expected one of: ppm, pfm, exr");
+        std::process::exit(1);
+    })
+}
+
+// Reconstruction filter, e.g. `--filter mitchell --filter-radius 2`
+fn filter_from_args() -> Filter {
+    let name = arg_value("--filter").unwrap_or_else(|| "box".to_string());
+    // A radius of zero splats nothing, and the Gaussian's weights go NaN
+    let radius = match arg_value("--filter-radius") {
+        Some(radius) => radius
+            .parse()
+            .ok()
+            .filter(|radius: &f32| radius.is_finite() && *radius > 0.0)
+            .unwrap_or_else(|| {
+                eprintln!("Invalid filter radius '{radius}', expected a positive number");
+                std::process::exit(1);
+            }),
+        None if name == "box" => 0.5,
+        None => 1.5,
+    };
+
+    Filter::from_name(&name, radius).unwrap_or_else(|| {
+        eprintln!("Unknown filter '{name}', expected one of: box, tent, gaussian, mitchell, blackman-harris");
+        std::process::exit(1);
+    })
+}
+
+// Sample generator, `--sampler independent|stratified|halton|sobol`
+fn sampler_from_args() -> SamplerKind {
+    let name = arg_value("--sampler").unwrap_or_else(|| "sobol".to_string());
+
+    SamplerKind::from_name(&name).unwrap_or_else(|| {
+        eprintln!(
+            "Unknown sampler '{name}', expected one of: independent, stratified, halton, sobol"
+        );
+        std::process::exit(1);
+    })
+}
+
+// Parse the value of a numeric flag, or fall back to `default`
+fn number_from_args<T: std::str::FromStr>(flag: &str, default: T) -> T {
+    match arg_value(flag) {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value '{value}' for {flag}");
+            std::process::exit(1);
+        }),
+        None => default,
+    }
+}
+
+// A length of time in seconds
+fn seconds_from_args(flag: &str, default: f32) -> Duration {
+    let seconds = number_from_args(flag, default);
+    Duration::try_from_secs_f32(seconds).unwrap_or_else(|_| {
+        eprintln!("{flag} expects a number of seconds, not {seconds}");
+        std::process::exit(1);
+    })
+}
+
+fn adaptive_from_args() -> Option<AdaptiveSampling> {
+    arg_value("--adaptive")?;
+    Some(AdaptiveSampling {
+        min_samples: number_from_args("--min-spp", 16),
+        max_samples: number_from_args("--max-spp", 256),
+        threshold: number_from_args("--adaptive", 0.01),
+    })
+}
 
-// TODO: Don't do recursion
-fn ray_color(ray: &Ray, world: &HittableList, depth: i32) -> Color {
-    if depth <= 0 {
-        return Color::zero();
+// e.g. `--exposure 1 --tonemap extended-reinhard --white 8 --dither`
+fn output_transform_from_args() -> OutputTransform {
+    let name = arg_value("--tonemap").unwrap_or_else(|| "clamp".to_string());
+    let white = number_from_args("--white", 4.0);
+    let tone_mapper = ToneMapper::from_name(&name, white).unwrap_or_else(|| {
+        eprintln!("Unknown tone mapper '{name}', expected one of: clamp, reinhard, extended-reinhard, aces, agx");
+        std::process::exit(1);
+    });
+
+    let name = arg_value("--display").unwrap_or_else(|| "srgb".to_string());
+    let display = ColorSpace::from_name(&name).unwrap_or_else(|| {
+        eprintln!("Unknown color space '{name}', expected one of: srgb, p3, rec2020, acescg");
+        std::process::exit(1);
+    });
+
+    OutputTransform {
+        exposure: number_from_args("--exposure", 0.0),
+        tone_mapper,
+        display,
+        dither: has_flag("--dither"),
+    }
+}
+
+fn physical_camera_from_args() -> Option<PhysicalCamera> {
+    if !["--iso", "--shutter", "--f-number"]
+        .iter()
+        .any(|flag| has_flag(flag))
+    {
+        return None;
+    }
+    let defaults = PhysicalCamera::default();
+    Some(PhysicalCamera {
+        iso: number_from_args("--iso", defaults.iso),
+        shutter_speed: number_from_args("--shutter", defaults.shutter_speed),
+        f_number: number_from_args("--f-number", defaults.f_number),
+        ..defaults
+    })
+}
+
+// e.g. `--projection fisheye-equisolid --fov 180`
+fn projection_from_args() -> Projection {
+    let name = arg_value("--projection").unwrap_or_else(|| "perspective".to_string());
+    let fov = number_from_args("--fov", 180.0);
+    Projection::from_name(&name, fov).unwrap_or_else(|| {
+        eprintln!(
+            "Unknown projection '{name}', expected one of: perspective, orthographic, fisheye-equidistant, \
+             fisheye-equisolid, equirectangular, cubemap"
+        );
+        std::process::exit(1);
+    })
+}
+
+// e.g. `--stereo top-bottom --interocular 0.064 --convergence 2`
+fn stereo_from_args() -> Option<Stereo> {
+    let name = arg_value("--stereo")?;
+    let layout = match name.as_str() {
+        "side-by-side" => StereoLayout::SideBySide,
+        "top-bottom" => StereoLayout::TopBottom,
+        _ => {
+            eprintln!("Unknown stereo layout '{name}', expected one of: side-by-side, top-bottom");
+            std::process::exit(1);
+        }
+    };
+    Some(Stereo {
+        interocular: number_from_args("--interocular", 0.064),
+        convergence: number_from_args("--convergence", 1.0),
+        layout,
+    })
+}
+
+fn lens_from_args() -> Option<LensSystem> {
+    let name = arg_value("--lens")?;
+    let mut lens = if name == "double-gauss" {
+        LensSystem::double_gauss()
+    } else {
+        let text = std::fs::read_to_string(&name).unwrap_or_else(|error| {
+            eprintln!("Can't read lens '{name}': {error}");
+            std::process::exit(1);
+        });
+        LensSystem::parse(&text).unwrap_or_else(|error| {
+            eprintln!("Invalid lens '{name}': {error}");
+            std::process::exit(1);
+        })
+    };
+    lens.blades = number_from_args("--blades", 0);
+    Some(lens)
+}
+
+// Comma separated numbers, as in `--distortion -0.1,0.02,0,0`
+fn numbers_from_args(flag: &str) -> Option<Vec<f32>> {
+    let values = arg_value(flag)?;
+    let numbers: Result<Vec<f32>, _> = values
+        .split(',')
+        .map(|value| value.trim().parse())
+        .collect();
+    Some(numbers.unwrap_or_else(|_| {
+        eprintln!("Invalid value '{values}' for {flag}");
+        std::process::exit(1);
+    }))
+}
+
+// `--resolution WxH` and the like
+fn resolution_from_args(flag: &str) -> Option<(usize, usize)> {
+    let value = arg_value(flag)?;
+    let resolution = value
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .filter(|&(width, height)| width > 0 && height > 0);
+    if resolution.is_none() {
+        eprintln!("Invalid value '{value}' for {flag}, expected e.g. 1920x1080");
+        std::process::exit(1);
     }
-    if let Some(hit) = world.hit(ray, 0.001, f32::INFINITY) {
-        if let Some((albedo, scattered)) = hit.material.scatter(ray, &hit) {
-            return albedo * ray_color(&scattered, world, depth - 1);
+    resolution
+}
+
+// Calibrated camera for an image of `width` by `height`, or of the
+// calibration's own size when that is given
+fn calibrated_camera_from_args(width: usize, height: usize) -> Option<Camera> {
+    let intrinsics = match numbers_from_args("--intrinsics")?[..] {
+        [fx, fy, cx, cy] => [[fx, 0.0, cx], [0.0, fy, cy], [0.0, 0.0, 1.0]],
+        [fx, fy, cx, cy, skew] => [[fx, skew, cx], [0.0, fy, cy], [0.0, 0.0, 1.0]],
+        _ => {
+            eprintln!("--intrinsics expects fx,fy,cx,cy and optionally the skew");
+            std::process::exit(1);
+        }
+    };
+    let mut extrinsic = [
+        [1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ];
+    if let Some(values) = numbers_from_args("--extrinsic") {
+        if values.len() != 12 && values.len() != 16 {
+            eprintln!("--extrinsic expects 12 or 16 values");
+            std::process::exit(1);
         }
-        else {
-            return Color::zero();
+        for (i, value) in values.into_iter().enumerate() {
+            extrinsic[i / 4][i % 4] = value;
         }
     }
-    let direction = unit_vector(&ray.direction);
-    let t = 0.5 * (direction.y + 1.0);
-    (1.0 - t)
-        * Color {
-            x: 1.0,
-            y: 1.0,
-            z: 1.0,
+
+    let (width, height) =
+        resolution_from_args("--calibration-resolution").unwrap_or((width, height));
+    let mut camera = Camera::from_calibration(&intrinsics, &extrinsic, width, height);
+    if let Some(coefficients) = numbers_from_args("--distortion") {
+        let distortion = Distortion::from_coefficients(&coefficients).unwrap_or_else(|| {
+            eprintln!("--distortion expects k1,k2,p1,p2 and optionally k3");
+            std::process::exit(1);
+        });
+        camera = camera.with_distortion(distortion);
+    }
+    Some(camera)
+}
+
+// `--crop x0,y0,x1,y1` in pixels, or `--crop-window` with fractions of the
+// image size
+fn crop_from_args() -> Option<CropWindow> {
+    let (flag, normalized) = if has_flag("--crop") {
+        ("--crop", false)
+    } else {
+        ("--crop-window", true)
+    };
+    let values = numbers_from_args(flag)?;
+    let [x0, y0, x1, y1] = values[..] else {
+        eprintln!("{flag} expects x0,y0,x1,y1");
+        std::process::exit(1);
+    };
+    if normalized {
+        Some(CropWindow::Normalized { x0, y0, x1, y1 })
+    } else {
+        let [x0, y0, x1, y1] = [x0, y0, x1, y1].map(|value| value as usize);
+        Some(CropWindow::Pixels(PixelBounds { x0, y0, x1, y1 }))
+    }
+}
+
+// Flags that don't change what gets rendered, with and without a value.
+// The seed and sample counts are checked separately where they matter.
+const RUN_FLAGS: [&str; 19] = [
+    "--seed",
+    "--spp",
+    "--adaptive",
+    "--min-spp",
+    "--max-spp",
+    "--pass-spp",
+    "--checkpoint",
+    "--checkpoint-interval",
+    "--resume",
+    "--time-budget",
+    "--target-noise",
+    "--report",
+    "--format",
+    "--sample-counts",
+    "--aovs",
+    "--exposure",
+    "--tonemap",
+    "--white",
+    "--display",
+];
+const RUN_SWITCHES: [&str; 3] = ["--denoise", "--dither", "--pad"];
+
+// Hash of the arguments that decide the scene, camera and integrator, for
+// telling whether renders can be combined. Flags are sorted with their
+// values first, so their order doesn't matter.
+fn scene_hash_from_args() -> u64 {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut kept = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        if RUN_FLAGS.contains(&args[i].as_str()) {
+            i += 2;
+            continue;
         }
-        + t * Color {
-            x: 0.5,
-            y: 0.7,
-            z: 1.0,
+        if !RUN_SWITCHES.contains(&args[i].as_str()) {
+            match (args[i].starts_with("--"), args.get(i + 1)) {
+                (true, Some(value)) if !value.starts_with("--") => {
+                    kept.push(format!("{} {value}", args[i]));
+                    i += 1;
+                }
+                _ => kept.push(args[i].clone()),
+            }
         }
+        i += 1;
+    }
+    kept.sort();
+    let bytes: Vec<u64> = kept.join(" ").bytes().map(u64::from).collect();
+    hash(&bytes)
+}
+
+fn checkpoint_from_args() -> Option<CheckpointSettings> {
+    let path = arg_value("--checkpoint").or_else(|| arg_value("--resume"))?;
+    Some(CheckpointSettings {
+        path: path.into(),
+        interval: seconds_from_args("--checkpoint-interval", 60.0),
+    })
+}
+
+fn aovs_from_args() -> Vec<Aov> {
+    let Some(names) = arg_value("--aovs") else {
+        return vec![];
+    };
+    if names == "all" {
+        return Aov::ALL.to_vec();
+    }
+    names
+        .split(',')
+        .map(|name| {
+            Aov::from_name(name).unwrap_or_else(|| {
+                let known: Vec<&str> = Aov::ALL.iter().map(|aov| aov.name()).collect();
+                eprintln!(
+                    "Unknown AOV '{name}', expected all or some of: {}",
+                    known.join(", ")
+                );
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+// The `merge` subcommand, see the top of the file
+fn merge_from_args() -> std::io::Result<()> {
+    let paths: Vec<String> = std::env::args()
+        .skip(2)
+        .take_while(|arg| !arg.starts_with("--"))
+        .collect();
+    let mut checkpoints = vec![];
+    for path in &paths {
+        checkpoints.push(Checkpoint::load(Path::new(path)).unwrap_or_else(|error| {
+            eprintln!("Can't read '{path}': {error}");
+            std::process::exit(1);
+        }));
+    }
+    let merged = merge(checkpoints).unwrap_or_else(|error| {
+        eprintln!("Can't merge: {error}");
+        std::process::exit(1);
+    });
+    if let Some(path) = arg_value("--save") {
+        merged.save(Path::new(&path))?;
+    }
+
+    let format = format_from_args();
+    let buffer = BufWriter::new(std::io::stdout().lock());
+    match format {
+        Format::Exr => write_exr(buffer, &rgb_channels(&merged.image, ""))?,
+        _ => encode(buffer, &merged.image, format, &output_transform_from_args())?,
+    }
+    if let Some(path) = arg_value("--sample-counts") {
+        let counts = merged
+            .image
+            .sample_counts(merged.image.samples.iter().copied().max().unwrap_or(0));
+        encode(
+            BufWriter::new(File::create(path)?),
+            &counts,
+            format,
+            &OutputTransform::default(),
+        )?;
+    }
+    Ok(())
 }
 
 fn main() -> std::io::Result<()> {
+    if std::env::args().nth(1).as_deref() == Some("merge") {
+        return merge_from_args();
+    }
+
     let stdout = std::io::stdout();
     let mut buffer = BufWriter::new(stdout.lock());
 
     // Image
-    let aspect_ratio = 16.0 / 9.0;
-    let width: u16 = 400;
-    let height: u16 = (width as f32 / aspect_ratio) as u16;
-    let samples_per_pixel = 64;
+    let projection = projection_from_args();
+    let aspect_ratio = match projection {
+        Projection::Equirectangular => 2.0,
+        Projection::CubeMap => 6.0,
+        _ => 16.0 / 9.0,
+    };
+    let default_width: usize = if projection == Projection::CubeMap {
+        768
+    } else {
+        400
+    };
+    let (eye_width, eye_height) = resolution_from_args("--resolution").unwrap_or((
+        default_width,
+        (default_width as f32 / aspect_ratio) as usize,
+    ));
+    let (mut width, mut height) = (eye_width, eye_height);
+    let stereo = stereo_from_args();
+    match stereo.map(|stereo| stereo.layout) {
+        Some(StereoLayout::SideBySide) => width *= 2,
+        Some(StereoLayout::TopBottom) => height *= 2,
+        None => {}
+    }
+    let time_budget = arg_value("--time-budget").map(|_| seconds_from_args("--time-budget", 0.0));
+    let target_noise = arg_value("--target-noise").map(|_| number_from_args("--target-noise", 0.0));
+    // Time and noise decide when to stop, so only a generous cap by default
+    let stops_early = time_budget.is_some() || target_noise.is_some();
+    let samples_per_pixel = number_from_args("--spp", if stops_early { 65536 } else { 64 });
     let max_depth = 32;
 
+    let integrator = integrator_from_args(max_depth);
+    let format = format_from_args();
+    let filter = filter_from_args();
+    let sampler = sampler_from_args();
+    let seed = number_from_args("--seed", 0);
+    let adaptive = adaptive_from_args();
+    let crop = crop_from_args();
+    let checkpoint = checkpoint_from_args();
+    let samples_per_pass = match checkpoint {
+        Some(_) => Some(number_from_args("--pass-spp", 1)),
+        None => arg_value("--pass-spp").map(|_| number_from_args("--pass-spp", 1)),
+    };
+    let aovs = aovs_from_args();
+    let transform = output_transform_from_args();
+
     // World
     let mut world = HittableList { objects: vec![] };
-    
-    let mat_ground: Rc<Lambertian> = Rc::new(Lambertian{albedo: Color{x: 0.8, y: 0.8, z: 0.0}});
-    let mat_center: Rc<Lambertian> = Rc::new(Lambertian{albedo: Color{x: 0.1, y: 0.2, z: 0.5}});
-    let mat_left: Rc<Dielectric> = Rc::new(Dielectric{ior: 1.5});
-    let mat_right: Rc<Metal> = Rc::new(Metal{albedo: Color{x: 0.8, y: 0.6, z: 0.2}, fuzz: 0.0});
+
+    // The space the colors below were picked in
+    let input = ColorSpace::Srgb;
+    let mat_ground: Arc<Lambertian> = Arc::new(Lambertian {
+        albedo: input.rgb(0.8, 0.8, 0.0),
+    });
+    let mat_center: Arc<Lambertian> = Arc::new(Lambertian {
+        albedo: input.rgb(0.1, 0.2, 0.5),
+    });
+    let mat_left: Arc<Dielectric> = Arc::new(Dielectric::new(1.5));
+    let mat_right: Arc<Conductor> = Arc::new(Conductor::gold(0.1));
 
     world.add(Box::new(Sphere {
         center: Vec3 {
@@ -70,36 +578,44 @@ fn main() -> std::io::Result<()> {
             z: -1.0,
         },
         radius: 100.0,
-        material: Rc::<Lambertian>::clone(&mat_ground),
+        material: Arc::<Lambertian>::clone(&mat_ground),
+        inside_out: false,
     }));
-    world.add(Box::new(Sphere {
+    let center = Box::new(Sphere {
         center: Vec3 {
             x: 0.0,
             y: 0.0,
             z: -1.0,
         },
         radius: 0.5,
-        material: Rc::<Lambertian>::clone(&mat_center),
-    }));
+        material: Arc::<Lambertian>::clone(&mat_center),
+        inside_out: false,
+    });
+    let velocity = numbers_from_args("--motion").map(|values| match values[..] {
+        [x, y, z] => Vec3 { x, y, z },
+        _ => {
+            eprintln!("--motion expects x,y,z");
+            std::process::exit(1);
+        }
+    });
+    match velocity {
+        Some(velocity) => world.add(Box::new(Moving {
+            object: center,
+            velocity,
+        })),
+        None => world.add(center),
+    }
 
-    world.add(Box::new(Sphere {
-        center: Vec3 {
+    world.add(Box::new(hollow_sphere(
+        Vec3 {
             x: -1.0,
             y: 0.0,
             z: -1.0,
         },
-        radius: 0.5,
-        material: Rc::<Dielectric>::clone(&mat_left),
-    }));
-    world.add(Box::new(Sphere {
-        center: Vec3 {
-            x: -1.0,
-            y: 0.0,
-            z: -1.0,
-        },
-        radius: -0.4,
-        material: Rc::<Dielectric>::clone(&mat_left),
-    }));
+        0.5,
+        0.1,
+        Arc::<Dielectric>::clone(&mat_left),
+    )));
 
     world.add(Box::new(Sphere {
         center: Vec3 {
@@ -108,51 +624,155 @@ fn main() -> std::io::Result<()> {
             z: -1.0,
         },
         radius: 0.5,
-        material: Rc::<Metal>::clone(&mat_right),
+        material: Arc::<Conductor>::clone(&mat_right),
+        inside_out: false,
     }));
 
     // Camera
-    let cam = Camera::new();
-
-    // Create a variable to store the buffer size when printing to standard
-    // error. Macro `eprint!` doesn't return num bytes so we will get the
-    // length of a string to 'hack' how C would use:
-    // `std::cerr << "\rfoo" << std::flush;`
-    let mut buffer_size;
-
-    // Write out the ppm header to stdout
-    write!(buffer, "P3\n{width} {height}\n255\n").expect("Failed to write header...");
-
-    buffer.flush().unwrap();
-
-    let mut rng = rand::thread_rng();
-
-    // The render loop, we will iterate over the image from top to bottom,
-    // then from left to right along the pixel row, row will be called a
-    // "scanline" from now,
-    for j in (0..height).rev() {
-        // Output progress for scanlines, to give us feedback in case the
-        // render freezes...
-        let buf = format!("\rScanlines remaining: {j}");
-        buffer_size = buf.len() + 1;
-        eprint!("{:buffer_size$}", buf);
-
-        for i in 0..width {
-            let mut color = Color::zero();
-
-            for _s in 0..samples_per_pixel {
-                let u = (i as f32 + rng.gen::<f32>()) / (width as f32 - 1.0);
-                let v = (j as f32 + rng.gen::<f32>()) / (height as f32 - 1.0);
-                let ray = cam.get_ray(u, v);
-                color += ray_color(&ray, &world, max_depth);
-            }
-            write_color(buffer.get_mut(), &color, samples_per_pixel);
+    let mut cam = calibrated_camera_from_args(eye_width, eye_height)
+        .unwrap_or_else(|| {
+            let forward = Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            };
+            let up = Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            };
+            Camera::look_at(
+                Vec3::zero(),
+                forward,
+                up,
+                90.0,
+                eye_width as f32 / eye_height as f32,
+            )
+        })
+        .with_projection(projection);
+    if let Some(stereo) = stereo {
+        cam = cam.with_stereo(stereo);
+    }
+    if let Some(physical) = physical_camera_from_args() {
+        cam = cam.with_physical(&physical);
+    }
+    if let Some(lens) = lens_from_args() {
+        cam = cam.with_lens(lens).unwrap_or_else(|error| {
+            eprintln!("Can't use the lens: {error}");
+            std::process::exit(1);
+        });
+    }
+    cam.spectral = has_flag("--spectral");
+    if velocity.is_some() && cam.shutter_close == cam.shutter_open {
+        cam.shutter_close = cam.shutter_open + 1.0;
+    }
+
+    let settings = RenderSettings {
+        width,
+        height,
+        samples_per_pixel,
+        adaptive,
+        integrator,
+        filter,
+        sampler,
+        seed,
+        crop,
+        samples_per_pass,
+        checkpoint,
+        time_budget,
+        target_noise,
+        scene_hash: scene_hash_from_args(),
+        progress: true,
+    };
+    let (mut image, mut features, report) = match arg_value("--resume") {
+        Some(path) => {
+            let result = Checkpoint::load(Path::new(&path))
+                .map_err(|error| error.to_string())
+                .and_then(|checkpoint| Renderer::resume(&world, &cam, &settings, checkpoint));
+            result.unwrap_or_else(|error| {
+                eprintln!("Can't resume from '{path}': {error}");
+                std::process::exit(1);
+            })
         }
+        None => Renderer::render_with_report(&world, &cam, &settings),
+    };
+    eprintln!(
+        "{} passes, {:.1} spp, noise {:.4}, stopped by {}",
+        report.passes,
+        report.samples_per_pixel,
+        report.noise,
+        report.stop.name()
+    );
+    if let Some(path) = arg_value("--report") {
+        report.write(BufWriter::new(File::create(path)?))?;
     }
 
-    eprint!("\nRender Finished\n");
+    // A crop window is written on its own, or with `--pad` back in place
+    // in the full frame
+    let crop_bounds = crop.map(|crop| crop.bounds(width, height));
+    if let Some(bounds) = crop_bounds {
+        image = image.crop(&bounds);
+        features = features.crop(&bounds);
+    }
+    let pad_bounds = crop_bounds.filter(|_| has_flag("--pad"));
+    let output = |image: Image| match pad_bounds {
+        Some(bounds) if format != Format::Exr => image.pad(&bounds, width, height),
+        _ => image,
+    };
+
+    let max_samples = if stops_early {
+        image.samples.iter().copied().max().unwrap_or(0)
+    } else {
+        adaptive.map_or(samples_per_pixel, |adaptive| adaptive.max_samples)
+    };
+    let counts = image.sample_counts(max_samples);
+
+    if has_flag("--denoise") {
+        image = Denoiser::default().denoise(&image, &features);
+    }
 
-    buffer.flush().unwrap();
+    if format == Format::Exr {
+        let layers: Vec<(Aov, Image)> =
+            aovs.iter().map(|&aov| (aov, features.layer(aov))).collect();
+        let mut channels = rgb_channels(&image, "");
+        for (aov, layer) in &layers {
+            for (component, name) in aov.channels().iter().enumerate() {
+                channels.push(Channel {
+                    name: format!("{}.{name}", aov.name()),
+                    image: layer,
+                    component,
+                });
+            }
+        }
+        match pad_bounds {
+            Some(bounds) => write_exr_window(&mut buffer, &channels, width, height, &bounds)?,
+            None => write_exr(&mut buffer, &channels)?,
+        }
+    } else {
+        encode(&mut buffer, &output(image), format, &transform)?;
+        for &aov in &aovs {
+            let layer = match format {
+                Format::Ppm => features.preview(aov),
+                _ => features.layer(aov),
+            };
+            let file = BufWriter::new(File::create(format!(
+                "aov_{}.{}",
+                aov.name(),
+                format.extension()
+            ))?);
+            encode(file, &output(layer), format, &OutputTransform::default())?;
+        }
+    }
+
+    if let Some(path) = arg_value("--sample-counts") {
+        let file = BufWriter::new(File::create(path)?);
+        match (format, pad_bounds) {
+            (Format::Exr, Some(bounds)) => {
+                write_exr_window(file, &rgb_channels(&counts, ""), width, height, &bounds)?
+            }
+            _ => encode(file, &output(counts), format, &OutputTransform::default())?,
+        }
+    }
 
     Ok(())
 }
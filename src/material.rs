@@ -1,76 +1,1142 @@
-use rand::prelude::*;
-use rand::distributions::Standard;
-
-use crate::ray::Ray;
-use crate::color::Color;
+use crate::color::{luminance, Color};
 use crate::hittable::HitRecord;
+use crate::microfacet::*;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::spectrum::{rgb_to_spectrum, LAMBDA_D};
+use crate::texture::Parameter;
+use crate::thin_film::*;
 use crate::vector::*;
+use std::sync::Arc;
 
 pub struct Lambertian {
     pub albedo: Color,
 }
 
+// Fuzzy reflection, kept around for older scenes. `Conductor` is the
+// physically based replacement.
 pub struct Metal {
     pub albedo: Color,
     pub fuzz: f32,
 }
 
+// Rough metal using a GGX microfacet distribution and complex IOR Fresnel.
+// Roughness can differ along the two tangent directions for brushed looks.
+pub struct Conductor {
+    pub eta: Color,
+    pub k: Color,
+    pub distribution: TrowbridgeReitz,
+}
+
+impl Conductor {
+    pub fn new(eta: Color, k: Color, roughness: f32) -> Conductor {
+        Conductor::anisotropic(eta, k, roughness, roughness)
+    }
+
+    pub fn anisotropic(eta: Color, k: Color, roughness_u: f32, roughness_v: f32) -> Conductor {
+        Conductor {
+            eta,
+            k,
+            distribution: TrowbridgeReitz::from_roughness(roughness_u, roughness_v),
+        }
+    }
+
+    // Presets, RGB values sampled from measured spectral data at roughly
+    // 650, 550 and 450 nm.
+    pub fn gold(roughness: f32) -> Conductor {
+        Conductor::new(
+            Color {
+                x: 0.143,
+                y: 0.374,
+                z: 1.442,
+            },
+            Color {
+                x: 3.983,
+                y: 2.385,
+                z: 1.603,
+            },
+            roughness,
+        )
+    }
+
+    pub fn copper(roughness: f32) -> Conductor {
+        Conductor::new(
+            Color {
+                x: 0.200,
+                y: 0.924,
+                z: 1.102,
+            },
+            Color {
+                x: 3.912,
+                y: 2.452,
+                z: 2.142,
+            },
+            roughness,
+        )
+    }
+
+    pub fn aluminum(roughness: f32) -> Conductor {
+        Conductor::new(
+            Color {
+                x: 1.657,
+                y: 0.880,
+                z: 0.521,
+            },
+            Color {
+                x: 9.224,
+                y: 6.270,
+                z: 4.837,
+            },
+            roughness,
+        )
+    }
+
+    pub fn silver(roughness: f32) -> Conductor {
+        Conductor::new(
+            Color {
+                x: 0.155,
+                y: 0.117,
+                z: 0.138,
+            },
+            Color {
+                x: 4.828,
+                y: 3.122,
+                z: 2.147,
+            },
+            roughness,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fresnel {
+    Schlick,
+    Exact,
+}
+
+// Index of refraction, optionally varying with wavelength for dispersion.
+// Wavelengths are in nanometers, the Cauchy and Sellmeier coefficients use
+// micrometers as is customary in glass catalogs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ior {
+    Constant(f32),
+    // n = a + b / lambda^2
+    Cauchy { a: f32, b: f32 },
+    // n^2 = 1 + sum b_i lambda^2 / (lambda^2 - c_i)
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Ior {
+    // Schott N-BK7, the usual crown glass
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.039612, 0.2317923, 1.010469],
+        c: [0.006000699, 0.02001791, 103.5607],
+    };
+
+    pub const DIAMOND: Ior = Ior::Sellmeier {
+        b: [0.3306, 4.3356, 0.0],
+        c: [0.030625, 0.011236, 0.0],
+    };
+
+    // Cauchy fit through the IOR at the d line and the Abbe number, which
+    // is how lens prescriptions and glass catalogs usually give dispersion
+    pub fn abbe(n_d: f32, abbe: f32) -> Ior {
+        // Hydrogen F and C lines, in micrometers
+        let (lambda_f, lambda_c, lambda_d) = (0.4861, 0.6563, LAMBDA_D / 1000.0);
+        let b = (n_d - 1.0) / abbe / (1.0 / (lambda_f * lambda_f) - 1.0 / (lambda_c * lambda_c));
+        Ior::Cauchy {
+            a: n_d - b / (lambda_d * lambda_d),
+            b,
+        }
+    }
+
+    // IOR at `wavelength`, or at the sodium D line when not rendering
+    // spectrally.
+    pub fn at(&self, wavelength: Option<f32>) -> f32 {
+        let micrometers = wavelength.unwrap_or(LAMBDA_D) / 1000.0;
+        let lambda2 = micrometers * micrometers;
+        match *self {
+            Ior::Constant(ior) => ior,
+            Ior::Cauchy { a, b } => a + b / lambda2,
+            Ior::Sellmeier { b, c } => {
+                let sum: f32 = (0..3).map(|i| b[i] * lambda2 / (lambda2 - c[i])).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+impl From<f32> for Ior {
+    fn from(ior: f32) -> Self {
+        Ior::Constant(ior)
+    }
+}
+
 pub struct Dielectric {
-    pub ior: f32, // Index of refraction
+    pub ior: Ior, // Index of refraction
+    pub fresnel: Fresnel,
+    // Absorption coefficient per unit distance travelled inside, zero for
+    // clear glass. Higher values in a channel tint the glass away from it.
+    pub absorption: Color,
+    // Where dielectrics overlap the one with the highest priority wins, see
+    // `Medium`.
+    pub priority: u32,
 }
 
 impl Dielectric {
+    pub fn new(ior: impl Into<Ior>) -> Dielectric {
+        Dielectric {
+            ior: ior.into(),
+            fresnel: Fresnel::Schlick,
+            absorption: Color::zero(),
+            priority: 0,
+        }
+    }
+
     // https://raytracing.github.io/books/RayTracingInOneWeekend.html#dielectrics/schlickapproximation
     fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
         let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-        let r0 = r0*r0;
-        return r0 * (1.0 - r0) * (1.0 - cosine).powf(5.0);
+        let r0 = r0 * r0;
+        r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
+    }
+
+    fn fresnel(&self, cosine: f32, refraction_ratio: f32) -> f32 {
+        match self.fresnel {
+            Fresnel::Schlick => Dielectric::reflectance(cosine, refraction_ratio),
+            Fresnel::Exact => fresnel_dielectric(cosine, 1.0 / refraction_ratio),
+        }
+    }
+}
+
+// Glass with a GGX rough interface, for frosted or sandblasted looks.
+// https://www.cs.cornell.edu/~srm/publications/EGSR07-btdf.pdf
+pub struct RoughDielectric {
+    pub ior: f32,
+    pub distribution: TrowbridgeReitz,
+    pub absorption: Color,
+    pub priority: u32,
+}
+
+impl RoughDielectric {
+    pub fn new(ior: f32, roughness: f32) -> RoughDielectric {
+        RoughDielectric {
+            ior,
+            distribution: TrowbridgeReitz::from_roughness(roughness, roughness),
+            absorption: Color::zero(),
+            priority: 0,
+        }
     }
 }
 
-pub trait Material {
-    fn scatter(&self, in_ray: &Ray, hit: &HitRecord) -> Option<(Color, Ray)>; 
+// Principled "uber" material loosely following the Disney BSDF. A clearcoat
+// layer sits on top of a blend between metal and dielectric, the dielectric
+// being either glass or diffuse with a specular layer. Each layer is picked
+// stochastically so the weights never add energy.
+// https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
+pub struct Principled {
+    pub base_color: Parameter,
+    pub metallic: Parameter,
+    pub roughness: Parameter,
+    // Dielectric reflectance at normal incidence, 0.5 maps to 4%
+    pub specular: Parameter,
+    pub clearcoat: Parameter,
+    pub clearcoat_roughness: Parameter,
+    pub sheen: Parameter,
+    pub sheen_tint: Parameter,
+    pub transmission: Parameter,
+    pub ior: f32,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Principled {
+            base_color: 0.8.into(),
+            metallic: 0.0.into(),
+            roughness: 0.5.into(),
+            specular: 0.5.into(),
+            clearcoat: 0.0.into(),
+            clearcoat_roughness: 0.03.into(),
+            sheen: 0.0.into(),
+            sheen_tint: 0.5.into(),
+            transmission: 0.0.into(),
+            ior: 1.5,
+        }
+    }
+}
+
+impl Material for Principled {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let frame = Onb::from_w(&hit.normal);
+        let wo = frame.to_local(&-unit_vector(&in_ray.direction));
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let scattered = |wi: Vec3| Ray {
+            origin: hit.point,
+            direction: frame.to_world(&wi),
+            ..*in_ray
+        };
+
+        let base_color = self.base_color.evaluate(hit);
+        let metallic = self.metallic.scalar(hit).clamp(0.0, 1.0);
+        let roughness = self.roughness.scalar(hit).clamp(0.0, 1.0);
+        let distribution = TrowbridgeReitz::from_roughness(roughness, roughness);
+
+        // Clearcoat, a fixed IOR 1.5 layer reflecting with probability of
+        // its Fresnel term.
+        let clearcoat = self.clearcoat.scalar(hit).clamp(0.0, 1.0);
+        if clearcoat > 0.0 {
+            let coat_roughness = self.clearcoat_roughness.scalar(hit).clamp(0.0, 1.0);
+            let coat = TrowbridgeReitz::from_roughness(coat_roughness, coat_roughness);
+            if sampler.get_1d() < clearcoat * fresnel_dielectric(wo.z, 1.5) {
+                let (_, wi, shadowing) = coat.sample_reflection(&wo, sampler.get_2d())?;
+                return Some((
+                    Color {
+                        x: shadowing,
+                        y: shadowing,
+                        z: shadowing,
+                    },
+                    scattered(wi),
+                ));
+            }
+        }
+
+        // Metal, tinted by the base color
+        if sampler.get_1d() < metallic {
+            let (wm, wi, shadowing) = distribution.sample_reflection(&wo, sampler.get_2d())?;
+            return Some((
+                fresnel_schlick(&base_color, dot(&wo, &wm)) * shadowing,
+                scattered(wi),
+            ));
+        }
+
+        // Glass, tinted by the base color on the way through
+        let transmission = self.transmission.scalar(hit).clamp(0.0, 1.0);
+        if sampler.get_1d() < transmission {
+            let eta = if hit.front_face {
+                self.ior
+            } else {
+                1.0 / self.ior
+            };
+            let (wi, shadowing) =
+                distribution.sample_dielectric(&wo, eta, sampler.get_2d(), sampler.get_1d())?;
+            let tint = if wi.z < 0.0 {
+                base_color
+            } else {
+                Color {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                }
+            };
+            return Some((shadowing * tint, scattered(wi)));
+        }
+
+        // Dielectric specular on top of diffuse
+        let f0 = 0.08 * self.specular.scalar(hit).clamp(0.0, 1.0);
+        let specular = fresnel_schlick(
+            &Color {
+                x: f0,
+                y: f0,
+                z: f0,
+            },
+            wo.z,
+        )
+        .x;
+        if sampler.get_1d() < specular {
+            let (_, wi, shadowing) = distribution.sample_reflection(&wo, sampler.get_2d())?;
+            return Some((
+                Color {
+                    x: shadowing,
+                    y: shadowing,
+                    z: shadowing,
+                },
+                scattered(wi),
+            ));
+        }
+
+        // Cosine sampled diffuse, giving way to the sheen retro-reflection
+        // at grazing angles. The sheen takes its share from the diffuse
+        // rather than adding to it.
+        let mut wi = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        } + sample_unit_vector(sampler.get_2d());
+        if wi.near_zero() {
+            wi = Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            };
+        }
+        let wi = unit_vector(&wi);
+        let mut attenuation = base_color;
+        let sheen = self.sheen.scalar(hit).max(0.0);
+        if sheen > 0.0 {
+            let half = unit_vector(&(wi + wo));
+            let tint_amount = self.sheen_tint.scalar(hit).clamp(0.0, 1.0);
+            let brightness = luminance(&base_color);
+            let tint = if brightness > 0.0 {
+                base_color / brightness
+            } else {
+                Color {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                }
+            };
+            let sheen_color = (1.0 - tint_amount) + tint_amount * tint;
+            let sheen_color = Color {
+                x: sheen_color.x.min(1.0),
+                y: sheen_color.y.min(1.0),
+                z: sheen_color.z.min(1.0),
+            };
+            let amount = (sheen * (1.0 - dot(&wi, &half)).powi(5)).min(1.0);
+            attenuation = (1.0 - amount) * attenuation + amount * sheen_color;
+        }
+        Some((attenuation, scattered(wi)))
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Color {
+        self.base_color.evaluate(hit)
+    }
+}
+
+// Blend between two materials, `factor` is the probability of picking
+// `second`. A texture mask makes for things like rust patches on metal.
+pub struct MixMaterial {
+    pub first: Arc<dyn Material>,
+    pub second: Arc<dyn Material>,
+    pub factor: Parameter,
+}
+
+impl Material for MixMaterial {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        if sampler.get_1d() < self.factor.scalar(hit) {
+            self.second.scatter(in_ray, hit, sampler)
+        } else {
+            self.first.scatter(in_ray, hit, sampler)
+        }
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Color {
+        let factor = self.factor.scalar(hit).clamp(0.0, 1.0);
+        (1.0 - factor) * self.first.albedo(hit) + factor * self.second.albedo(hit)
+    }
+
+    fn emitted(&self, hit: &HitRecord) -> Color {
+        let factor = self.factor.scalar(hit).clamp(0.0, 1.0);
+        (1.0 - factor) * self.first.emitted(hit) + factor * self.second.emitted(hit)
+    }
+}
+
+// A clear dielectric layer, e.g. varnish, on top of any base material.
+// Light entering the coat bounces between the base and the underside of
+// the coat until it gets out, so energy reflected internally is not simply
+// thrown away. Long walks are ended by Russian roulette, which loses
+// nothing on average.
+pub struct Coated {
+    pub base: Arc<dyn Material>,
+    pub ior: f32,
+    pub distribution: TrowbridgeReitz,
+    // Thickness of the layer and its absorption per unit distance, for
+    // tinted coats. The product is what matters.
+    pub thickness: f32,
+    pub absorption: Color,
+}
+
+impl Coated {
+    // Internal bounces before Russian roulette starts
+    const ROULETTE_BOUNCES: u32 = 8;
+
+    pub fn new(base: Arc<dyn Material>, ior: f32) -> Coated {
+        Coated {
+            base,
+            ior,
+            distribution: TrowbridgeReitz::from_roughness(0.0, 0.0),
+            thickness: 0.0,
+            absorption: Color::zero(),
+        }
+    }
+}
+
+impl Material for Coated {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let unit_direction = unit_vector(&in_ray.direction);
+        let frame = Onb::from_w(&hit.normal);
+        let wo = frame.to_local(&-unit_direction);
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        // Reflect off the top of the coat
+        if sampler.get_1d() < fresnel_dielectric(wo.z, self.ior) {
+            let (_, wi, shadowing) = self.distribution.sample_reflection(&wo, sampler.get_2d())?;
+            return Some((
+                Color {
+                    x: shadowing,
+                    y: shadowing,
+                    z: shadowing,
+                },
+                Ray {
+                    origin: hit.point,
+                    direction: frame.to_world(&wi),
+                    ..*in_ray
+                },
+            ));
+        }
+
+        // Otherwise refract in and walk between the base and the coat
+        let mut direction = refract(&unit_direction, &hit.normal, 1.0 / self.ior);
+        let mut attenuation = transmittance(
+            &self.absorption,
+            self.thickness / dot(&-direction, &hit.normal).max(1e-4),
+        );
+        let mut bounces = 0;
+        loop {
+            let (base_attenuation, scattered) = self.base.scatter(
+                &Ray {
+                    origin: hit.point,
+                    direction,
+                    ..*in_ray
+                },
+                hit,
+                sampler,
+            )?;
+            attenuation *= base_attenuation;
+
+            let out = unit_vector(&scattered.direction);
+            let cos_out = dot(&out, &hit.normal);
+            if cos_out <= 0.0 {
+                // The base let the light through, nothing more for the coat to do
+                return Some((attenuation, scattered));
+            }
+            attenuation *= transmittance(&self.absorption, self.thickness / cos_out);
+
+            if sampler.get_1d() >= fresnel_dielectric(cos_out, 1.0 / self.ior) {
+                let exit = refract(&out, &-hit.normal, self.ior);
+                return Some((
+                    attenuation,
+                    Ray {
+                        origin: hit.point,
+                        direction: exit,
+                        ..*in_ray
+                    },
+                ));
+            }
+
+            // Reflected back down by the underside of the coat
+            direction = reflect(&out, &hit.normal);
+            attenuation *= transmittance(&self.absorption, self.thickness / cos_out);
+
+            // Carry on with a chance of the energy left, at most 95% so the
+            // walk ends, and make up for the walks that stopped
+            bounces += 1;
+            if bounces > Coated::ROULETTE_BOUNCES {
+                let survival = attenuation
+                    .x
+                    .max(attenuation.y)
+                    .max(attenuation.z)
+                    .min(0.95);
+                if sampler.get_1d() >= survival {
+                    return None;
+                }
+                attenuation = attenuation / survival;
+            }
+        }
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Color {
+        self.base.albedo(hit)
+    }
+}
+
+pub enum ThinFilmBase {
+    Dielectric { ior: f32 },
+    Conductor { eta: Color, k: Color },
+}
+
+// A thin transparent film on top of a smooth base, giving the iridescence of
+// soap bubbles, oil slicks and coated lenses. A dielectric base with IOR 1
+// is a free standing film, i.e. a bubble.
+pub struct ThinFilm {
+    // In nanometers, interesting colors show up between 100 and 1000
+    pub thickness: f32,
+    pub film_ior: f32,
+    pub base: ThinFilmBase,
+}
+
+impl Material for ThinFilm {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let unit_direction = unit_vector(&in_ray.direction);
+        let cos_theta = dot(&-unit_direction, &hit.normal).min(1.0);
+        let reflected = Ray {
+            origin: hit.point,
+            direction: reflect(&unit_direction, &hit.normal),
+            ..*in_ray
+        };
+
+        match self.base {
+            ThinFilmBase::Conductor { eta, k } => {
+                if !hit.front_face {
+                    return None;
+                }
+                let reflectance = match in_ray.wavelength {
+                    Some(wavelength) => {
+                        let substrate = Complex::new(
+                            rgb_to_spectrum(&eta, wavelength),
+                            rgb_to_spectrum(&k, wavelength),
+                        );
+                        let r = thin_film_reflectance(
+                            cos_theta,
+                            1.0,
+                            self.film_ior,
+                            self.thickness,
+                            substrate,
+                            wavelength,
+                        );
+                        Color { x: r, y: r, z: r }
+                    }
+                    None => {
+                        let substrate = [
+                            Complex::new(eta.x, k.x),
+                            Complex::new(eta.y, k.y),
+                            Complex::new(eta.z, k.z),
+                        ];
+                        thin_film_reflectance_rgb(
+                            cos_theta,
+                            1.0,
+                            self.film_ior,
+                            self.thickness,
+                            substrate,
+                        )
+                    }
+                };
+                Some((reflectance, reflected))
+            }
+            ThinFilmBase::Dielectric { ior } => {
+                // The film sits on the outside, so from within the base the
+                // light crosses it on the way out instead
+                let (incident_ior, substrate_ior) = if hit.front_face {
+                    (1.0, ior)
+                } else {
+                    (ior, 1.0)
+                };
+                let reflectance = match in_ray.wavelength {
+                    Some(wavelength) => {
+                        let r = thin_film_reflectance(
+                            cos_theta,
+                            incident_ior,
+                            self.film_ior,
+                            self.thickness,
+                            Complex::real(substrate_ior),
+                            wavelength,
+                        );
+                        Color { x: r, y: r, z: r }
+                    }
+                    None => thin_film_reflectance_rgb(
+                        cos_theta,
+                        incident_ior,
+                        self.film_ior,
+                        self.thickness,
+                        [Complex::real(substrate_ior); 3],
+                    ),
+                };
+
+                // Pick reflection or transmission by the average, then weight
+                // the channels so each ends up with its own reflectance
+                let probability = (reflectance.x + reflectance.y + reflectance.z) / 3.0;
+                if sampler.get_1d() < probability {
+                    return Some((reflectance / probability, reflected));
+                }
+                let direction = refract(&unit_direction, &hit.normal, incident_ior / substrate_ior);
+                Some((
+                    (1.0 - reflectance) / (1.0 - probability),
+                    Ray {
+                        origin: hit.point,
+                        direction,
+                        ..*in_ray
+                    },
+                ))
+            }
+        }
+    }
+}
+
+// Cloth, a diffuse base with a retro-reflective sheen lobe from the Charlie
+// distribution and Ashikhmin's visibility term, giving the soft rim of
+// velvet and other fibrous fabrics.
+// https://blog.selfshadow.com/publications/s2017-shading-course/imageworks/s2017_pbs_imageworks_sheen.pdf
+pub struct Sheen {
+    pub base_color: Parameter,
+    pub sheen_color: Parameter,
+    pub roughness: f32,
+}
+
+impl Sheen {
+    fn charlie(&self, cos_theta_h: f32) -> f32 {
+        let alpha = (self.roughness * self.roughness).max(1e-3);
+        let inverse_alpha = 1.0 / alpha;
+        let sin2_theta_h = (1.0 - cos_theta_h * cos_theta_h).max(0.0);
+        (2.0 + inverse_alpha) * sin2_theta_h.powf(0.5 * inverse_alpha)
+            / (2.0 * std::f32::consts::PI)
+    }
+
+    fn ashikhmin_visibility(cos_theta_o: f32, cos_theta_i: f32) -> f32 {
+        1.0 / (4.0 * (cos_theta_i + cos_theta_o - cos_theta_i * cos_theta_o))
+    }
+}
+
+impl Material for Sheen {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let wo = -unit_vector(&in_ray.direction);
+        let mut direction = hit.normal + sample_unit_vector(sampler.get_2d());
+        if direction.near_zero() {
+            direction = hit.normal;
+        }
+        let wi = unit_vector(&direction);
+
+        // Cosine sampling cancels the cosine and leaves pi times the BRDF
+        let half = unit_vector(&(wi + wo));
+        let cos_theta_o = dot(&wo, &hit.normal).max(1e-4);
+        let cos_theta_i = dot(&wi, &hit.normal).max(1e-4);
+        let sheen = std::f32::consts::PI
+            * self.charlie(dot(&half, &hit.normal))
+            * Sheen::ashikhmin_visibility(cos_theta_o, cos_theta_i);
+        let attenuation = self.base_color.evaluate(hit) + sheen * self.sheen_color.evaluate(hit);
+        Some((
+            attenuation,
+            Ray {
+                origin: hit.point,
+                direction: wi,
+                ..*in_ray
+            },
+        ))
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Color {
+        self.base_color.evaluate(hit)
+    }
+}
+
+// Beer-Lambert attenuation for light that travelled `distance` through a
+// medium with the given absorption coefficient.
+pub fn transmittance(absorption: &Color, distance: f32) -> Color {
+    Color {
+        x: (-absorption.x * distance).exp(),
+        y: (-absorption.y * distance).exp(),
+        z: (-absorption.z * distance).exp(),
+    }
+}
+
+// Emits `emit` from both sides and absorbs everything that hits it
+pub struct DiffuseLight {
+    pub emit: Parameter,
+}
+
+impl Material for DiffuseLight {
+    fn scatter(
+        &self,
+        _in_ray: &Ray,
+        _hit: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        None
+    }
+
+    fn emitted(&self, hit: &HitRecord) -> Color {
+        self.emit.evaluate(hit)
+    }
+}
+
+// The volume enclosed by a refractive material. For nested dielectrics,
+// e.g. liquid in a glass, the objects are modelled slightly overlapping and
+// the integrator only lets the medium with the highest priority exist in
+// the overlap, ignoring surfaces of lower priority media inside it.
+// Absorption is applied by the integrator to the distance travelled inside,
+// since the surfaces bounding a stretch of a medium might not be its own.
+// http://www.cs.jhu.edu/~cohen/Publications/nested.pdf
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Medium {
+    pub ior: f32,
+    pub priority: u32,
+    pub absorption: Color,
+}
+
+pub trait Material: Send + Sync {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)>;
+
+    // Refractive materials return the medium they enclose
+    fn medium(&self, _wavelength: Option<f32>) -> Option<Medium> {
+        None
+    }
+
+    // Base surface color, used by debug views, AOVs and the denoiser.
+    fn albedo(&self, _hit: &HitRecord) -> Color {
+        Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        }
+    }
+
+    // Light given off by the surface itself
+    fn emitted(&self, _hit: &HitRecord) -> Color {
+        Color::zero()
+    }
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, in_ray: &Ray, hit: &HitRecord) -> Option<(Color, Ray)> {
-        let mut scattered_direction: Vec3 = hit.normal + random_unit_vector();
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let mut scattered_direction: Vec3 = hit.normal + sample_unit_vector(sampler.get_2d());
         if scattered_direction.near_zero() {
             scattered_direction = hit.normal;
         }
-        let scattered = Ray{origin: hit.point, direction: scattered_direction};
-        return Some((self.albedo, scattered));
+        let scattered = Ray {
+            origin: hit.point,
+            direction: scattered_direction,
+            ..*in_ray
+        };
+        Some((self.albedo, scattered))
+    }
+
+    fn albedo(&self, _hit: &HitRecord) -> Color {
+        self.albedo
     }
 }
 
 impl Material for Metal {
-    fn scatter(&self, in_ray: &Ray, hit: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
         let reflected: Vec3 = reflect(&unit_vector(&in_ray.direction), &hit.normal);
-        let scattered = Ray{origin: hit.point, direction: reflected + self.fuzz*random_in_unit_sphere()};
+        let scattered = Ray {
+            origin: hit.point,
+            direction: reflected + self.fuzz * sample_unit_vector(sampler.get_2d()),
+            ..*in_ray
+        };
         if dot(&reflected, &hit.normal) > 0.0 {
             return Some((self.albedo, scattered));
         }
         None
     }
+
+    fn albedo(&self, _hit: &HitRecord) -> Color {
+        self.albedo
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let frame = Onb::from_w(&hit.normal);
+        let wo = frame.to_local(&-unit_vector(&in_ray.direction));
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let (wm, wi, shadowing) = self.distribution.sample_reflection(&wo, sampler.get_2d())?;
+        let attenuation = fresnel_conductor(dot(&wo, &wm), &self.eta, &self.k) * shadowing;
+        Some((
+            attenuation,
+            Ray {
+                origin: hit.point,
+                direction: frame.to_world(&wi),
+                ..*in_ray
+            },
+        ))
+    }
+
+    fn albedo(&self, _hit: &HitRecord) -> Color {
+        fresnel_conductor(1.0, &self.eta, &self.k)
+    }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, in_ray: &Ray, hit: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
         let unit_direction = unit_vector(&in_ray.direction);
+        let attenuation = Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
 
         let cos_theta = dot(&-unit_direction, &hit.normal).min(1.0);
-        let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
-        let refraction_ratio = if hit.front_face {1.0 / self.ior} else { self.ior };
+        let ior = self.ior.at(in_ray.wavelength);
+        let refraction_ratio = if hit.front_face {
+            hit.exterior_ior / ior
+        } else {
+            ior / hit.exterior_ior
+        };
 
         // If we cannot refract,
-        let random_double = StdRng::from_entropy().sample(Standard);
-        if refraction_ratio * sin_theta > 1.0 || Dielectric::reflectance(cos_theta, refraction_ratio) > random_double {
+        let random_double = sampler.get_1d();
+        if refraction_ratio * sin_theta > 1.0
+            || self.fresnel(cos_theta, refraction_ratio) > random_double
+        {
             let reflected = reflect(&unit_direction, &hit.normal);
-            return Some((Color{x: 1.0, y: 1.0, z: 1.0}, Ray{origin: hit.point, direction: reflected}));
+            return Some((
+                attenuation,
+                Ray {
+                    origin: hit.point,
+                    direction: reflected,
+                    ..*in_ray
+                },
+            ));
         }
 
         let refracted = refract(&unit_direction, &hit.normal, refraction_ratio);
-        Some((Color{x: 1.0, y: 1.0, z: 1.0}, Ray{origin: hit.point, direction: refracted}))
+        Some((
+            attenuation,
+            Ray {
+                origin: hit.point,
+                direction: refracted,
+                ..*in_ray
+            },
+        ))
+    }
+    fn medium(&self, wavelength: Option<f32>) -> Option<Medium> {
+        Some(Medium {
+            ior: self.ior.at(wavelength),
+            priority: self.priority,
+            absorption: self.absorption,
+        })
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(
+        &self,
+        in_ray: &Ray,
+        hit: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let frame = Onb::from_w(&hit.normal);
+        let wo = frame.to_local(&-unit_vector(&in_ray.direction));
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        // IOR on the far side over the IOR on the side we are coming from
+        let eta = if hit.front_face {
+            self.ior / hit.exterior_ior
+        } else {
+            hit.exterior_ior / self.ior
+        };
+        let (wi, shadowing) =
+            self.distribution
+                .sample_dielectric(&wo, eta, sampler.get_2d(), sampler.get_1d())?;
+        Some((
+            Color {
+                x: shadowing,
+                y: shadowing,
+                z: shadowing,
+            },
+            Ray {
+                origin: hit.point,
+                direction: frame.to_world(&wi),
+                ..*in_ray
+            },
+        ))
+    }
+
+    fn medium(&self, _wavelength: Option<f32>) -> Option<Medium> {
+        Some(Medium {
+            ior: self.ior,
+            priority: self.priority,
+            absorption: self.absorption,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hittable::Hittable;
+    use crate::material::*;
+    use crate::sampler::SamplerKind;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn test_schlick_reflectance() {
+        // Glass at normal incidence reflects 4%, and everything at grazing
+        let r = Dielectric::reflectance(1.0, 1.0 / 1.5);
+        assert!((r - 0.04).abs() < 1e-5);
+        let r = Dielectric::reflectance(0.0, 1.0 / 1.5);
+        assert!((r - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_exact_fresnel_reflectance() {
+        let r = fresnel_dielectric(1.0, 1.5);
+        assert!((r - 0.04).abs() < 1e-5);
+        let r = fresnel_dielectric(0.0, 1.5);
+        assert!((r - 1.0).abs() < 1e-5);
+
+        // 45 degrees from air into glass, Rs = 0.0920 and Rp = 0.0085
+        let r = fresnel_dielectric(std::f32::consts::FRAC_1_SQRT_2, 1.5);
+        assert!((r - 0.05025).abs() < 1e-4);
+
+        // Past the critical angle going from glass into air
+        let r = fresnel_dielectric(0.5, 1.0 / 1.5);
+        assert_eq!(r, 1.0);
+    }
+
+    #[test]
+    fn test_dielectric_fresnel_option() {
+        let schlick = Dielectric::new(1.5);
+        let exact = Dielectric {
+            fresnel: Fresnel::Exact,
+            ..Dielectric::new(1.5)
+        };
+        assert!((schlick.fresnel(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-5);
+        assert!((exact.fresnel(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-5);
+        assert!((exact.fresnel(0.0, 1.0 / 1.5) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_sellmeier_ior() {
+        // Catalog values for N-BK7 at the d line and the blue F line
+        assert!((Ior::BK7.at(None) - 1.5168).abs() < 1e-3);
+        assert!((Ior::BK7.at(Some(486.1)) - 1.5224).abs() < 1e-3);
+        assert_eq!(Ior::Constant(1.5).at(Some(400.0)), 1.5);
+    }
+
+    #[test]
+    fn test_coated_white_base_loses_no_energy() {
+        // Smooth glass over white Lambertian traps a lot of light inside
+        // by total internal reflection, all of which gets out eventually
+        let material = Arc::new(Coated::new(
+            Arc::new(Lambertian {
+                albedo: Color {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                },
+            }),
+            1.5,
+        ));
+        let sphere = Sphere::new(Vec3::zero(), 1.0, material.clone());
+        let down = Ray {
+            origin: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 2.0,
+            },
+            direction: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            wavelength: None,
+            time: 0.0,
+        };
+        let hit = sphere.hit(&down, 0.001, f32::INFINITY).unwrap();
+
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+        let count = 50000;
+        let mut total = 0.0;
+        for index in 0..count {
+            sampler.start_pixel_sample(0, 0, index);
+            if let Some((attenuation, _)) = material.scatter(&down, &hit, sampler.as_mut()) {
+                total += attenuation.y;
+            }
+        }
+        let albedo = total / count as f32;
+        assert!((albedo - 1.0).abs() < 0.01, "{albedo}");
+    }
+
+    #[test]
+    fn test_principled_sheen_conserves_energy() {
+        let material = Arc::new(Principled {
+            base_color: 1.0.into(),
+            sheen: 1.0.into(),
+            ..Default::default()
+        });
+        let sphere = Sphere::new(Vec3::zero(), 1.0, material.clone());
+        let down = Ray {
+            origin: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 2.0,
+            },
+            direction: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            wavelength: None,
+            time: 0.0,
+        };
+        let hit = sphere.hit(&down, 0.001, f32::INFINITY).unwrap();
+
+        // Sheen is strongest looking at the surface edge on
+        let grazing = Ray {
+            direction: Vec3 {
+                x: -1.0,
+                y: 0.0,
+                z: -0.05,
+            },
+            ..down
+        };
+        let mut sampler = SamplerKind::Independent.create(1, 0);
+        for index in 0..1000 {
+            sampler.start_pixel_sample(0, 0, index);
+            if let Some((attenuation, _)) = material.scatter(&grazing, &hit, sampler.as_mut()) {
+                assert!(
+                    attenuation.x <= 1.0 && attenuation.y <= 1.0 && attenuation.z <= 1.0,
+                    "{attenuation:?}"
+                );
+            }
+        }
     }
 }
@@ -0,0 +1,262 @@
+use std::f32::consts::PI;
+
+use crate::color::Color;
+use crate::vector::*;
+
+// Trowbridge-Reitz (GGX) microfacet distribution, all directions are given in
+// the local shading frame where the macro surface normal is +z.
+// https://jcgt.org/published/0003/02/03/paper.pdf
+#[derive(Debug, Clone, Copy)]
+pub struct TrowbridgeReitz {
+    pub alpha_x: f32,
+    pub alpha_y: f32,
+}
+
+impl TrowbridgeReitz {
+    // Artists tend to prefer roughness, alpha is its square
+    pub fn from_roughness(roughness_x: f32, roughness_y: f32) -> TrowbridgeReitz {
+        TrowbridgeReitz {
+            alpha_x: (roughness_x * roughness_x).max(1e-4),
+            alpha_y: (roughness_y * roughness_y).max(1e-4),
+        }
+    }
+
+    // Below this the lobe is so narrow we treat it as a perfect mirror
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+
+    pub fn d(&self, wm: &Vec3) -> f32 {
+        if wm.z <= 0.0 {
+            return 0.0;
+        }
+        let e = (wm.x / self.alpha_x).powi(2) + (wm.y / self.alpha_y).powi(2) + wm.z * wm.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    pub fn lambda(&self, w: &Vec3) -> f32 {
+        if w.z == 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2 = ((self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2)) / (w.z * w.z);
+        0.5 * (-1.0 + (1.0 + tan2).sqrt())
+    }
+
+    pub fn g1(&self, w: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height correlated masking-shadowing
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Sample a microfacet normal from the distribution of normals visible
+    // from `wo`, `u1` and `u2` are uniform in [0, 1).
+    // https://jcgt.org/published/0007/04/01/paper.pdf
+    pub fn sample_visible_normal(&self, wo: &Vec3, u1: f32, u2: f32) -> Vec3 {
+        let flip = wo.z < 0.0;
+        let wo = if flip { -*wo } else { *wo };
+
+        let vh = unit_vector(&Vec3 {
+            x: self.alpha_x * wo.x,
+            y: self.alpha_y * wo.y,
+            z: wo.z,
+        });
+        let length_squared = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if length_squared > 0.0 {
+            Vec3 {
+                x: -vh.y,
+                y: vh.x,
+                z: 0.0,
+            } / length_squared.sqrt()
+        } else {
+            Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }
+        };
+        let t2 = cross(&vh, &t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+        let wm = unit_vector(&Vec3 {
+            x: self.alpha_x * nh.x,
+            y: self.alpha_y * nh.y,
+            z: nh.z.max(1e-6),
+        });
+        if flip {
+            -wm
+        } else {
+            wm
+        }
+    }
+}
+
+impl TrowbridgeReitz {
+    // Sample a reflected direction off a visible microfacet. Returns the
+    // microfacet normal, the incident direction and the G2 / G1 weight left
+    // over once the sampling density cancels the rest of the BRDF.
+    pub fn sample_reflection(&self, wo: &Vec3, u: (f32, f32)) -> Option<(Vec3, Vec3, f32)> {
+        if self.is_smooth() {
+            let wm = Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            };
+            return Some((wm, reflect(&-*wo, &wm), 1.0));
+        }
+        let wm = self.sample_visible_normal(wo, u.0, u.1);
+        let wi = reflect(&-*wo, &wm);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        Some((wm, wi, self.g(wo, &wi) / self.g1(wo)))
+    }
+
+    // Sample reflection or transmission through a rough dielectric
+    // interface, `eta` being the IOR on the far side over the near side.
+    // Picking between the two proportionally to Fresnel cancels it out of
+    // the weight, leaving G2 / G1 as for reflection.
+    pub fn sample_dielectric(
+        &self,
+        wo: &Vec3,
+        eta: f32,
+        u: (f32, f32),
+        u_lobe: f32,
+    ) -> Option<(Vec3, f32)> {
+        let wm = if self.is_smooth() {
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }
+        } else {
+            self.sample_visible_normal(wo, u.0, u.1)
+        };
+
+        let reflectance = fresnel_dielectric(dot(wo, &wm), eta);
+        let wi = if u_lobe < reflectance {
+            let wi = reflect(&-*wo, &wm);
+            if wi.z <= 0.0 {
+                return None;
+            }
+            wi
+        } else {
+            let wi = refract(&-*wo, &wm, 1.0 / eta);
+            if wi.z >= 0.0 {
+                return None;
+            }
+            wi
+        };
+
+        if self.is_smooth() {
+            return Some((wi, 1.0));
+        }
+        Some((wi, self.g(wo, &wi) / self.g1(wo)))
+    }
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction
+// `eta + i k`, evaluated per channel.
+// https://seblagarde.wordpress.com/2013/04/29/memo-on-fresnel-equations/
+pub fn fresnel_conductor(cos_theta_i: f32, eta: &Color, k: &Color) -> Color {
+    Color {
+        x: fresnel_conductor_channel(cos_theta_i, eta.x, k.x),
+        y: fresnel_conductor_channel(cos_theta_i, eta.y, k.y),
+        z: fresnel_conductor_channel(cos_theta_i, eta.z, k.z),
+    }
+}
+
+// Schlick's approximation with a colored reflectance at normal incidence
+pub fn fresnel_schlick(f0: &Color, cos_theta_i: f32) -> Color {
+    let weight = (1.0 - cos_theta_i.clamp(0.0, 1.0)).powi(5);
+    *f0 + (1.0 - *f0) * weight
+}
+
+// Exact unpolarized Fresnel reflectance for a dielectric interface, `eta` is
+// the ratio of the IOR on the transmitted side over the incident side.
+// Returns 1 for total internal reflection.
+pub fn fresnel_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let cos_theta_i = cos_theta_i.clamp(0.0, 1.0);
+    let sin2_theta_t = (1.0 - cos_theta_i * cos_theta_i) / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+
+    let r_parallel = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perpendicular = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+fn fresnel_conductor_channel(cos_theta_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_theta_i.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_theta_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::microfacet::*;
+
+    #[test]
+    fn test_fresnel_conductor_normal_incidence() {
+        // At normal incidence the exact expression reduces to
+        // ((eta - 1)^2 + k^2) / ((eta + 1)^2 + k^2)
+        let eta = Color {
+            x: 0.143,
+            y: 0.374,
+            z: 1.442,
+        };
+        let k = Color {
+            x: 3.983,
+            y: 2.385,
+            z: 1.603,
+        };
+        let f = fresnel_conductor(1.0, &eta, &k);
+        let expected =
+            |eta: f32, k: f32| ((eta - 1.0).powi(2) + k * k) / ((eta + 1.0).powi(2) + k * k);
+        assert!((f.x - expected(eta.x, k.x)).abs() < 1e-5);
+        assert!((f.y - expected(eta.y, k.y)).abs() < 1e-5);
+        assert!((f.z - expected(eta.z, k.z)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_visible_normals_face_viewer() {
+        let distribution = TrowbridgeReitz::from_roughness(0.5, 0.2);
+        let wo = unit_vector(&Vec3 {
+            x: 0.6,
+            y: -0.3,
+            z: 0.4,
+        });
+        for i in 0..64 {
+            let u1 = (i as f32 + 0.5) / 64.0;
+            let u2 = ((i * 37) % 64) as f32 / 64.0;
+            let wm = distribution.sample_visible_normal(&wo, u1, u2);
+            assert!(wm.z > 0.0);
+            assert!(dot(&wo, &wm) >= -1e-4);
+            assert!((wm.length() - 1.0).abs() < 1e-4);
+        }
+    }
+}
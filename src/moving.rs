@@ -0,0 +1,112 @@
+use crate::hittable::*;
+use crate::ray::Ray;
+use crate::vector::Vec3;
+
+// Wraps an object so it moves at a constant `velocity`, in scene units per
+// second, from where it was modelled at time zero. Seen through a camera
+// with its shutter open for a while this gives motion blur.
+pub struct Moving {
+    pub object: Box<dyn Hittable>,
+    pub velocity: Vec3,
+}
+
+impl Hittable for Moving {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        // Move the ray back instead of the object forward
+        let offset = ray.time * self.velocity;
+        let moved = Ray {
+            origin: ray.origin - offset,
+            ..*ray
+        };
+        let mut hit = self.object.hit(&moved, t_min, t_max)?;
+        hit.point += offset;
+        Some(hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::material::Lambertian;
+    use crate::moving::*;
+    use crate::sphere::Sphere;
+    use crate::vector::*;
+
+    #[test]
+    fn test_hits_where_the_object_is_at_the_ray_time() {
+        let material = Arc::new(Lambertian {
+            albedo: Vec3 {
+                x: 0.5,
+                y: 0.5,
+                z: 0.5,
+            },
+        });
+        let moving = Moving {
+            object: Box::new(Sphere::new(
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -3.0,
+                },
+                0.5,
+                material,
+            )),
+            velocity: Vec3 {
+                x: 2.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+
+        // Down the middle of where it starts, then of where it is a second later
+        let start = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            wavelength: None,
+            time: 0.0,
+        };
+        let hit = moving.hit(&start, 0.001, f32::INFINITY).unwrap();
+        assert!(
+            (hit.point
+                - Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -2.5
+                })
+            .length()
+                < 1e-5
+        );
+        assert!(moving
+            .hit(&Ray { time: 1.0, ..start }, 0.001, f32::INFINITY)
+            .is_none());
+
+        let end = Ray {
+            origin: Vec3 {
+                x: 2.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            time: 1.0,
+            ..start
+        };
+        let hit = moving.hit(&end, 0.001, f32::INFINITY).unwrap();
+        assert!(
+            (hit.point
+                - Vec3 {
+                    x: 2.0,
+                    y: 0.0,
+                    z: -2.5
+                })
+            .length()
+                < 1e-5
+        );
+        assert!(moving
+            .hit(&Ray { time: 0.0, ..end }, 0.001, f32::INFINITY)
+            .is_none());
+    }
+}
@@ -1,9 +1,14 @@
 use crate::vector::Vec3;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    // Wavelength in nanometers carried by the path when rendering
+    // spectrally, `None` for plain RGB.
+    pub wavelength: Option<f32>,
+    // When the ray was sent out, within the shutter interval of the camera
+    pub time: f32,
 }
 
 impl Ray {
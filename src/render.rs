@@ -0,0 +1,648 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::aov::FeatureBuffers;
+use crate::camera::Camera;
+use crate::checkpoint::Checkpoint;
+use crate::color::Color;
+use crate::filter::Filter;
+use crate::hittable::Hittable;
+use crate::image::{Image, PixelBounds};
+use crate::integrator::{Features, Integrator, PathTracer};
+use crate::sampler::*;
+
+// Keep sampling each pixel until the relative standard error of its mean
+// drops below `threshold`, taking at least `min_samples` and at most
+// `max_samples`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f32,
+}
+
+// Part of the frame to render, in pixels or as fractions of the image
+// size. The camera still frames the full image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropWindow {
+    Pixels(PixelBounds),
+    Normalized { x0: f32, y0: f32, x1: f32, y1: f32 },
+}
+
+impl CropWindow {
+    // The pixels covered, at least partly, within a `width` by `height`
+    // image
+    pub fn bounds(&self, width: usize, height: usize) -> PixelBounds {
+        let bounds = match *self {
+            CropWindow::Pixels(bounds) => bounds,
+            CropWindow::Normalized { x0, y0, x1, y1 } => PixelBounds {
+                x0: (x0 * width as f32).floor() as usize,
+                y0: (y0 * height as f32).floor() as usize,
+                x1: (x1 * width as f32).ceil() as usize,
+                y1: (y1 * height as f32).ceil() as usize,
+            },
+        };
+        let x1 = bounds.x1.min(width);
+        let y1 = bounds.y1.min(height);
+        PixelBounds {
+            x0: bounds.x0.min(x1),
+            y0: bounds.y0.min(y1),
+            x1,
+            y1,
+        }
+    }
+}
+
+// Where and how often a progressive render saves its progress
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointSettings {
+    pub path: PathBuf,
+    // Saved after the first pass to end at least this long after the last
+    // save, and once more at the end
+    pub interval: Duration,
+}
+
+// Why a render stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // Every pixel got all its samples
+    Samples,
+    TimeBudget,
+    TargetNoise,
+}
+
+impl StopReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StopReason::Samples => "samples",
+            StopReason::TimeBudget => "time-budget",
+            StopReason::TargetNoise => "target-noise",
+        }
+    }
+}
+
+// What a render achieved, for when it's told to stop at a time or noise
+// level rather than a sample count
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderReport {
+    pub passes: u32,
+    // Average over the rendered pixels
+    pub samples_per_pixel: f32,
+    // See `Image::noise`
+    pub noise: f32,
+    // Time spent rendering, by this run only when resumed
+    pub elapsed: Duration,
+    pub stop: StopReason,
+}
+
+impl RenderReport {
+    // A JSON object
+    pub fn write(&self, mut writer: impl std::io::Write) -> std::io::Result<()> {
+        // JSON has no infinity, noise is null until it can be estimated
+        let noise = if self.noise.is_finite() {
+            self.noise.to_string()
+        } else {
+            "null".to_string()
+        };
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"passes\": {},", self.passes)?;
+        writeln!(
+            writer,
+            "  \"samples_per_pixel\": {},",
+            self.samples_per_pixel
+        )?;
+        writeln!(writer, "  \"noise\": {noise},")?;
+        writeln!(writer, "  \"seconds\": {},", self.elapsed.as_secs_f32())?;
+        writeln!(writer, "  \"stop\": \"{}\"", self.stop.name())?;
+        writeln!(writer, "}}")
+    }
+}
+
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: u32,
+    // Replaces the fixed `samples_per_pixel` when set
+    pub adaptive: Option<AdaptiveSampling>,
+    pub integrator: Box<dyn Integrator>,
+    pub filter: Filter,
+    pub sampler: SamplerKind,
+    // Renders with the same seed and settings come out identical
+    pub seed: u64,
+    // Only render this part of the image, the rest stays empty
+    pub crop: Option<CropWindow>,
+    // Render progressively, giving every pixel this many more samples per
+    // pass over the image, instead of all of them at once
+    pub samples_per_pass: Option<u32>,
+    pub checkpoint: Option<CheckpointSettings>,
+    // Stop after the pass that would be expected to run past this, or once
+    // the noise estimate is at most `target_noise`. `samples_per_pixel`
+    // is then only an upper limit, and passes default to one sample.
+    pub time_budget: Option<Duration>,
+    pub target_noise: Option<f32>,
+    // Identifies the scene and camera in checkpoints, so a render isn't
+    // continued with a different one
+    pub scene_hash: u64,
+    // Report the scanlines left on stderr
+    pub progress: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 400,
+            height: 225,
+            samples_per_pixel: 64,
+            adaptive: None,
+            integrator: Box::new(PathTracer {
+                max_depth: 32,
+                spectral: false,
+            }),
+            filter: Filter::default(),
+            sampler: SamplerKind::Sobol,
+            seed: 0,
+            crop: None,
+            samples_per_pass: None,
+            checkpoint: None,
+            time_budget: None,
+            target_noise: None,
+            scene_hash: 0,
+            progress: false,
+        }
+    }
+}
+
+pub struct Renderer;
+
+impl RenderSettings {
+    fn max_samples(&self) -> u32 {
+        match self.adaptive {
+            Some(adaptive) => adaptive.max_samples,
+            None => self.samples_per_pixel,
+        }
+    }
+
+    fn samples_per_pass(&self) -> u32 {
+        let stops_early = self.time_budget.is_some() || self.target_noise.is_some();
+        let default = if stops_early { 1 } else { self.max_samples() };
+        self.samples_per_pass.unwrap_or(default).max(1)
+    }
+
+    // Pixels to render, and those around them to sample as well so that the
+    // filter gives the pixels on the edge of a crop window the same samples
+    // it would in a full render
+    fn bounds(&self) -> (PixelBounds, PixelBounds) {
+        let bounds = match self.crop {
+            Some(crop) => crop.bounds(self.width, self.height),
+            None => PixelBounds::full(self.width, self.height),
+        };
+        let margin = self.filter.radius().ceil() as usize;
+        (bounds, bounds.expand(margin, self.width, self.height))
+    }
+}
+
+impl Renderer {
+    pub fn render(scene: &dyn Hittable, camera: &Camera, settings: &RenderSettings) -> Image {
+        Renderer::render_with_features(scene, camera, settings).0
+    }
+
+    // Also returns the first hit features of every pixel, for AOVs and the
+    // denoiser
+    pub fn render_with_features(
+        scene: &dyn Hittable,
+        camera: &Camera,
+        settings: &RenderSettings,
+    ) -> (Image, FeatureBuffers) {
+        let (image, features, _) = Renderer::render_with_report(scene, camera, settings);
+        (image, features)
+    }
+
+    // Also reports the samples and noise achieved, and why it stopped
+    pub fn render_with_report(
+        scene: &dyn Hittable,
+        camera: &Camera,
+        settings: &RenderSettings,
+    ) -> (Image, FeatureBuffers, RenderReport) {
+        Renderer::render_from(scene, camera, settings, Renderer::start(settings))
+    }
+
+    // Continues a render from a checkpoint of it, the result is the same as
+    // if it had never stopped. Fails when the checkpoint is of another scene
+    // or was made with different settings.
+    pub fn resume(
+        scene: &dyn Hittable,
+        camera: &Camera,
+        settings: &RenderSettings,
+        mut checkpoint: Checkpoint,
+    ) -> Result<(Image, FeatureBuffers, RenderReport), String> {
+        let mismatch = |what: &str| Err(format!("checkpoint was rendered with a different {what}"));
+        if checkpoint.scene_hash != settings.scene_hash {
+            return mismatch("scene");
+        }
+        if (checkpoint.width(), checkpoint.height()) != (settings.width, settings.height) {
+            return mismatch("resolution");
+        }
+        if checkpoint.seed != settings.seed || checkpoint.sampler != settings.sampler {
+            return mismatch("seed or sampler");
+        }
+        if checkpoint.samples_per_pixel != settings.max_samples()
+            || checkpoint.samples_per_pass != settings.samples_per_pass()
+        {
+            return mismatch("sample count");
+        }
+        if checkpoint.adaptive != settings.adaptive {
+            return mismatch("adaptive sampling");
+        }
+        checkpoint.image.filter = settings.filter;
+        Ok(Renderer::render_from(scene, camera, settings, checkpoint))
+    }
+
+    // Empty accumulation buffers for a render with `settings`
+    pub fn start(settings: &RenderSettings) -> Checkpoint {
+        Checkpoint {
+            scene_hash: settings.scene_hash,
+            seed: settings.seed,
+            merged_seeds: vec![],
+            sampler: settings.sampler,
+            samples_per_pixel: settings.max_samples(),
+            samples_per_pass: settings.samples_per_pass(),
+            adaptive: settings.adaptive,
+            passes: 0,
+            next_sample: vec![0; settings.width * settings.height],
+            image: Image::with_filter(settings.width, settings.height, settings.filter),
+            features: FeatureBuffers::new(settings.width, settings.height),
+        }
+    }
+
+    // Passes until every pixel has all its samples, or the time budget or
+    // target noise is met, saving checkpoints along the way
+    fn render_from(
+        scene: &dyn Hittable,
+        camera: &Camera,
+        settings: &RenderSettings,
+        mut state: Checkpoint,
+    ) -> (Image, FeatureBuffers, RenderReport) {
+        let (bounds, _) = settings.bounds();
+        let started = Instant::now();
+        let mut last_save = started;
+        let stop = loop {
+            let pass_started = Instant::now();
+            if Renderer::render_pass(scene, camera, settings, &mut state) == 0 {
+                break StopReason::Samples;
+            }
+            let pass_time = pass_started.elapsed();
+            if let Some(checkpoint) = &settings.checkpoint {
+                if last_save.elapsed() >= checkpoint.interval {
+                    Renderer::save(&state, checkpoint);
+                    last_save = Instant::now();
+                }
+            }
+
+            if settings
+                .target_noise
+                .is_some_and(|target| state.image.noise(&bounds) <= target)
+            {
+                break StopReason::TargetNoise;
+            }
+            // Passes take about as long as the last one did
+            if settings
+                .time_budget
+                .is_some_and(|budget| started.elapsed() + pass_time > budget)
+            {
+                break StopReason::TimeBudget;
+            }
+        };
+        if let Some(checkpoint) = &settings.checkpoint {
+            Renderer::save(&state, checkpoint);
+        }
+
+        let report = RenderReport {
+            passes: state.passes,
+            samples_per_pixel: state.image.average_samples(&bounds),
+            noise: state.image.noise(&bounds),
+            elapsed: started.elapsed(),
+            stop,
+        };
+        if settings.progress {
+            eprint!("\nRender Finished\n");
+        }
+        let (image, features) = Renderer::finish(settings, state);
+        (image, features, report)
+    }
+
+    // A failed save shouldn't take the render down with it
+    fn save(state: &Checkpoint, checkpoint: &CheckpointSettings) {
+        if let Err(error) = state.save(&checkpoint.path) {
+            eprintln!(
+                "\nCouldn't save checkpoint to {}: {error}",
+                checkpoint.path.display()
+            );
+        }
+    }
+
+    // Takes up to `samples_per_pass` more samples in every pixel that isn't
+    // done yet, returning how many were taken in total
+    pub fn render_pass(
+        scene: &dyn Hittable,
+        camera: &Camera,
+        settings: &RenderSettings,
+        state: &mut Checkpoint,
+    ) -> u64 {
+        let Checkpoint {
+            image,
+            features,
+            next_sample,
+            ..
+        } = state;
+        let max_samples = settings.max_samples();
+        let mut sampler = settings.sampler.create(max_samples, settings.seed);
+        let (bounds, sampled) = settings.bounds();
+        let mut taken = 0;
+
+        let width = settings.width as f32;
+        let height = settings.height as f32;
+
+        // Create a variable to store the buffer size when printing to standard
+        // error. Macro `eprint!` doesn't return num bytes so we will get the
+        // length of a string to 'hack' how C would use:
+        // `std::cerr << "\rfoo" << std::flush;`
+        let mut buffer_size;
+
+        // The render loop, we will iterate over the image from top to bottom,
+        // then from left to right along the pixel row, row will be called a
+        // "scanline" from now,
+        for y in sampled.y0..sampled.y1 {
+            // Output progress for scanlines, to give us feedback in case the
+            // render freezes...
+            if settings.progress {
+                let buf = format!(
+                    "\rPass {}, scanlines remaining: {}",
+                    state.passes + 1,
+                    sampled.y1 - 1 - y
+                );
+                buffer_size = buf.len() + 1;
+                eprint!("{:buffer_size$}", buf);
+            }
+
+            for x in sampled.x0..sampled.x1 {
+                let inside = bounds.contains(x, y);
+                // Pixels carry on from the samples they took in earlier
+                // passes
+                let index = image.index(x, y);
+                let start = next_sample[index];
+                for s in start..(start + state.samples_per_pass).min(max_samples) {
+                    if let Some(adaptive) = settings.adaptive {
+                        if s >= adaptive.min_samples
+                            && image.relative_error(x, y) < adaptive.threshold
+                        {
+                            break;
+                        }
+                    }
+                    sampler.start_pixel_sample(x, y, s);
+                    next_sample[index] = s + 1;
+                    taken += 1;
+
+                    // Camera v goes up while image rows go down
+                    sampler.set_dimension(FILM_DIMENSION);
+                    let (jitter_x, jitter_y) = sampler.get_2d();
+                    let film_x = x as f32 + jitter_x;
+                    let film_y = y as f32 + jitter_y;
+                    let Some((ray, weight)) =
+                        camera.get_ray(film_x / width, 1.0 - film_y / height, sampler.as_mut())
+                    else {
+                        image.splat(film_x, film_y, Color::zero());
+                        if inside {
+                            features.add(x, y, &Features::miss());
+                        }
+                        continue;
+                    };
+                    let (color, mut first_hit) =
+                        settings
+                            .integrator
+                            .li_features(&ray, scene, sampler.as_mut());
+                    first_hit.scale_light(camera.exposure * weight);
+                    image.splat(film_x, film_y, color * (camera.exposure * weight));
+                    if inside {
+                        features.add(x, y, &first_hit);
+                    }
+                }
+            }
+        }
+
+        if taken > 0 {
+            state.passes += 1;
+        }
+        taken
+    }
+
+    // The final image and features, with what the filter spread outside of
+    // a crop window dropped
+    pub fn finish(settings: &RenderSettings, state: Checkpoint) -> (Image, FeatureBuffers) {
+        let mut image = state.image;
+        if settings.crop.is_some() {
+            let (bounds, _) = settings.bounds();
+            image = image
+                .crop(&bounds)
+                .pad(&bounds, settings.width, settings.height);
+        }
+        (image, state.features)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::hittable_list::HittableList;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::render::*;
+    use crate::sphere::Sphere;
+    use crate::texture::Parameter;
+    use crate::vector::*;
+
+    // A gray sphere in front of the default camera
+    fn sphere_scene() -> (HittableList, Camera) {
+        let mut world = HittableList { objects: vec![] };
+        let material = Arc::new(Lambertian {
+            albedo: Color {
+                x: 0.5,
+                y: 0.5,
+                z: 0.5,
+            },
+        });
+        world.add(Box::new(Sphere::new(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            0.5,
+            material,
+        )));
+        (world, Camera::new())
+    }
+
+    #[test]
+    fn test_constant_radiance() {
+        // Looking around from inside a glowing sphere, every pixel sees the
+        // same radiance whatever the filter and sampler do with it
+        let mut world = HittableList { objects: vec![] };
+        let emit = Color {
+            x: 0.2,
+            y: 0.7,
+            z: 1.5,
+        };
+        let light = Arc::new(DiffuseLight {
+            emit: Parameter::Constant(emit),
+        });
+        world.add(Box::new(Sphere::new(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            10.0,
+            light,
+        )));
+        let settings = RenderSettings {
+            width: 8,
+            height: 6,
+            samples_per_pixel: 5,
+            filter: Filter::from_name("gaussian", 1.5).unwrap(),
+            ..Default::default()
+        };
+        let image = Renderer::render(&world, &Camera::new(), &settings);
+        for y in 0..6 {
+            for x in 0..8 {
+                assert!(
+                    (image.get(x, y) - emit).length() < 1e-5,
+                    "{x} {y} {:?}",
+                    image.get(x, y)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_crop_matches_full_render() {
+        let (world, camera) = sphere_scene();
+        let settings = RenderSettings {
+            width: 16,
+            height: 9,
+            samples_per_pixel: 4,
+            filter: Filter::from_name("gaussian", 1.5).unwrap(),
+            ..Default::default()
+        };
+        let full = Renderer::render(&world, &camera, &settings);
+
+        let crop = CropWindow::Normalized {
+            x0: 0.25,
+            y0: 0.3,
+            x1: 0.6,
+            y1: 0.8,
+        };
+        let cropped = Renderer::render(
+            &world,
+            &camera,
+            &RenderSettings {
+                crop: Some(crop),
+                ..settings
+            },
+        );
+        let bounds = crop.bounds(16, 9);
+        assert_eq!(
+            bounds,
+            PixelBounds {
+                x0: 4,
+                y0: 2,
+                x1: 10,
+                y1: 8
+            }
+        );
+        for y in 0..9 {
+            for x in 0..16 {
+                let expected = if bounds.contains(x, y) {
+                    full.get(x, y)
+                } else {
+                    Color::zero()
+                };
+                assert_eq!(cropped.get(x, y), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_resume_matches_uninterrupted_render() {
+        let (world, camera) = sphere_scene();
+        let settings = RenderSettings {
+            width: 12,
+            height: 8,
+            samples_per_pixel: 6,
+            samples_per_pass: Some(2),
+            filter: Filter::from_name("tent", 1.0).unwrap(),
+            scene_hash: 42,
+            ..Default::default()
+        };
+        let (full, _) = Renderer::render_with_features(&world, &camera, &settings);
+
+        // Stop after the first pass, write the checkpoint out and carry on
+        // from what was read back
+        let mut state = Renderer::start(&settings);
+        Renderer::render_pass(&world, &camera, &settings, &mut state);
+        let mut bytes = vec![];
+        state.write(&mut bytes).unwrap();
+        let checkpoint = Checkpoint::read(&bytes[..]).unwrap();
+        assert_eq!(checkpoint.passes, 1);
+        let (resumed, _, _) = Renderer::resume(&world, &camera, &settings, checkpoint).unwrap();
+        assert_eq!(resumed.pixels, full.pixels);
+        assert_eq!(resumed.samples, vec![6; 12 * 8]);
+
+        let other = RenderSettings {
+            scene_hash: 7,
+            ..settings
+        };
+        let checkpoint = Checkpoint::read(&bytes[..]).unwrap();
+        assert!(Renderer::resume(&world, &camera, &other, checkpoint).is_err());
+        let adaptive = AdaptiveSampling {
+            min_samples: 2,
+            max_samples: 6,
+            threshold: 0.1,
+        };
+        let other = RenderSettings {
+            adaptive: Some(adaptive),
+            scene_hash: 42,
+            ..other
+        };
+        let checkpoint = Checkpoint::read(&bytes[..]).unwrap();
+        assert!(Renderer::resume(&world, &camera, &other, checkpoint).is_err());
+    }
+
+    #[test]
+    fn test_stop_conditions() {
+        let (world, camera) = sphere_scene();
+        let settings = RenderSettings {
+            width: 8,
+            height: 6,
+            samples_per_pixel: 4096,
+            target_noise: Some(0.05),
+            ..Default::default()
+        };
+        let (image, _, report) = Renderer::render_with_report(&world, &camera, &settings);
+        assert_eq!(report.stop, StopReason::TargetNoise);
+        assert!(report.noise <= 0.05);
+        assert!(report.samples_per_pixel < 4096.0);
+        assert_eq!(report.samples_per_pixel, report.passes as f32);
+        assert_eq!(report.noise, image.noise(&PixelBounds::full(8, 6)));
+
+        // Any pass runs over a budget of nothing, but there's always one
+        let budget = RenderSettings {
+            target_noise: None,
+            time_budget: Some(Duration::ZERO),
+            ..settings
+        };
+        let (_, _, report) = Renderer::render_with_report(&world, &camera, &budget);
+        assert_eq!(report.stop, StopReason::TimeBudget);
+        assert_eq!(report.passes, 1);
+        assert!(report.noise.is_infinite());
+    }
+}
@@ -0,0 +1,520 @@
+use std::sync::OnceLock;
+
+// Every path consumes sampler dimensions in the same order, so the well
+// distributed early dimensions of low discrepancy sequences end up where
+// they matter most: the position on the film, the lens, the wavelength, the
+// time and then a fixed block for each bounce. Within a bounce the material takes
+// what it needs in its own fixed order. Only the coated random walk can run
+// past the end of its block, deep inside the layer where it hardly matters.
+pub const FILM_DIMENSION: u32 = 0;
+pub const LENS_DIMENSION: u32 = 2;
+pub const WAVELENGTH_DIMENSION: u32 = 4;
+pub const TIME_DIMENSION: u32 = 5;
+pub const FIRST_BOUNCE_DIMENSION: u32 = 6;
+pub const DIMENSIONS_PER_BOUNCE: u32 = 16;
+
+pub fn bounce_dimension(bounce: u32) -> u32 {
+    FIRST_BOUNCE_DIMENSION + bounce * DIMENSIONS_PER_BOUNCE
+}
+
+// Source of the numbers in [0, 1) driving all random decisions of a path.
+// Samplers are deterministic given their seed, the pixel, the sample index
+// and the dimension, so a render can be reproduced exactly.
+pub trait Sampler {
+    // Start generating the `index`th sample of pixel (x, y), from the first
+    // dimension
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32);
+
+    // Jump to a given dimension of the current sample
+    fn set_dimension(&mut self, dimension: u32);
+
+    fn get_1d(&mut self) -> f32;
+
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+        }
+    }
+
+    // Stratification is built around the number of samples each pixel is
+    // expected to get, the others don't care.
+    pub fn create(&self, samples_per_pixel: u32, seed: u64) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel, seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+// 64 bit finalizer from MurmurHash3, good enough to decorrelate anything we
+// feed it.
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, &v| {
+        mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15))
+    })
+}
+
+// The top 24 bits of `bits` as a float in [0, 1)
+fn to_unit_float(bits: u32) -> f32 {
+    (bits >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+// State shared by all samplers, which pixel sample and dimension we're at
+#[derive(Debug, Clone, Copy, Default)]
+struct SampleState {
+    seed: u64,
+    pixel_hash: u64,
+    index: u32,
+    dimension: u32,
+}
+
+impl SampleState {
+    fn start(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel_hash = hash(&[x as u64, y as u64, self.seed]);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_dimension(&mut self) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        dimension
+    }
+
+    // Two dimensions starting at an even one, so pairs line up the same way
+    // however many 1D samples were taken before.
+    fn next_pair(&mut self) -> u32 {
+        self.dimension += self.dimension % 2;
+        let dimension = self.dimension;
+        self.dimension += 2;
+        dimension
+    }
+}
+
+// Plain uniform random numbers, a hash of everything that identifies them
+pub struct IndependentSampler {
+    state: SampleState,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler {
+            state: SampleState {
+                seed,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn value(&self, dimension: u32) -> f32 {
+        let bits = hash(&[
+            self.state.pixel_hash,
+            self.state.index as u64,
+            dimension as u64,
+        ]);
+        to_unit_float(bits as u32)
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.state.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        self.value(dimension)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.next_pair();
+        (self.value(dimension), self.value(dimension + 1))
+    }
+}
+
+// Element `index` of a random permutation of 0..length chosen by `seed`,
+// without building the permutation.
+// https://graphics.pixar.com/library/MultiJitteredSampling/paper.pdf
+fn permutation_element(mut index: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170893d);
+        index ^= seed >> 16;
+        index ^= (index & w) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929eb3f);
+        index ^= seed >> 23;
+        index ^= (index & w) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935fa69);
+        index ^= (index & w) >> 11;
+        index = index.wrapping_mul(0x74dcb303);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0x9e501cc3);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0xc860a3df);
+        index &= w;
+        index ^= index >> 5;
+        if index < length {
+            return index.wrapping_add(seed) % length;
+        }
+    }
+}
+
+// Jittered sampling, each dimension is split into one stratum per sample
+// (a grid for pairs) and each sample gets a random stratum of its own.
+// Samples past `samples_per_pixel` start over with a fresh permutation.
+pub struct StratifiedSampler {
+    state: SampleState,
+    samples_per_pixel: u32,
+    x_strata: u32,
+    y_strata: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> StratifiedSampler {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        let x_strata = (samples_per_pixel as f32).sqrt().floor().max(1.0) as u32;
+        let y_strata = samples_per_pixel.div_ceil(x_strata);
+        StratifiedSampler {
+            state: SampleState {
+                seed,
+                ..Default::default()
+            },
+            samples_per_pixel,
+            x_strata,
+            y_strata,
+        }
+    }
+
+    // Stratum of the current sample in `dimension` along with the hash used
+    // to jitter within it
+    fn stratum(&self, dimension: u32, strata: u32) -> (u32, u64) {
+        let round = self.state.index / self.samples_per_pixel;
+        let h = hash(&[self.state.pixel_hash, dimension as u64, round as u64]);
+        let index = self.state.index % self.samples_per_pixel;
+        (permutation_element(index, strata, h as u32), h)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.state.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        let (stratum, h) = self.stratum(dimension, self.samples_per_pixel);
+        let jitter = to_unit_float(mix_bits(h ^ self.state.index as u64) as u32);
+        ((stratum as f32 + jitter) / self.samples_per_pixel as f32).min(1.0 - f32::EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.next_pair();
+        let (stratum, h) = self.stratum(dimension, self.x_strata * self.y_strata);
+        let jitter = mix_bits(h ^ self.state.index as u64);
+        let x = (stratum % self.x_strata) as f32 + to_unit_float(jitter as u32);
+        let y = (stratum / self.x_strata) as f32 + to_unit_float((jitter >> 32) as u32);
+        (
+            (x / self.x_strata as f32).min(1.0 - f32::EPSILON),
+            (y / self.y_strata as f32).min(1.0 - f32::EPSILON),
+        )
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// Halton sequence, one prime base per dimension, with the digits randomly
+// shifted per pixel so neighbouring pixels don't share the same pattern.
+// Dimensions past the table of primes fall back to independent samples.
+pub struct HaltonSampler {
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            state: SampleState {
+                seed,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn value(&self, dimension: u32) -> f32 {
+        let h = hash(&[self.state.pixel_hash, dimension as u64]);
+        let Some(&base) = PRIMES.get(dimension as usize) else {
+            return to_unit_float(mix_bits(h ^ self.state.index as u64) as u32);
+        };
+
+        // Radical inverse with each digit shifted by a hashed amount. Keep
+        // going past the last non zero digit of the index, otherwise every
+        // sample would share the same shifted tail.
+        let inverse_base = 1.0 / base as f64;
+        let mut index = self.state.index as u64;
+        let mut scale = inverse_base;
+        let mut value = 0.0;
+        let mut digit_index = 0;
+        while scale > 1e-9 {
+            let shift = mix_bits(h ^ digit_index) % base as u64;
+            let digit = (index % base as u64 + shift) % base as u64;
+            value += digit as f64 * scale;
+            index /= base as u64;
+            scale *= inverse_base;
+            digit_index += 1;
+        }
+        (value as f32).min(1.0 - f32::EPSILON)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.state.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        self.value(dimension)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.next_pair();
+        (self.value(dimension), self.value(dimension + 1))
+    }
+}
+
+// Direction numbers for one dimension of the Sobol sequence, from the
+// degree `s` primitive polynomial with coefficients `a` and initial values
+// `m`.
+// https://web.maths.unsw.edu.au/~fkuo/sobol/joe-kuo-notes.pdf
+fn sobol_directions(s: usize, a: u32, m: &[u32]) -> [u32; 32] {
+    let mut v = [0u32; 32];
+    if s == 0 {
+        for (i, direction) in v.iter_mut().enumerate() {
+            *direction = 1 << (31 - i);
+        }
+        return v;
+    }
+    for i in 0..s {
+        v[i] = m[i] << (31 - i);
+    }
+    for i in s..32 {
+        v[i] = v[i - s] ^ (v[i - s] >> s);
+        for k in 1..s {
+            v[i] ^= ((a >> (s - 1 - k)) & 1) * v[i - k];
+        }
+    }
+    v
+}
+
+fn sobol_4d_directions() -> &'static [[u32; 32]; 4] {
+    static DIRECTIONS: OnceLock<[[u32; 32]; 4]> = OnceLock::new();
+    DIRECTIONS.get_or_init(|| {
+        [
+            sobol_directions(0, 0, &[]),
+            sobol_directions(1, 0, &[1]),
+            sobol_directions(2, 1, &[1, 3]),
+            sobol_directions(3, 1, &[1, 3, 1]),
+        ]
+    })
+}
+
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let directions = &sobol_4d_directions()[dimension];
+    let mut x = 0;
+    for (bit, direction) in directions.iter().enumerate() {
+        if (index >> bit) & 1 == 1 {
+            x ^= direction;
+        }
+    }
+    x
+}
+
+// Base 2 Owen scrambling through a hash that only lets lower bits affect
+// higher ones, applied to the reversed bits.
+// https://jcgt.org/published/0009/04/01/paper.pdf
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+// Owen scrambled Sobol, in the hash based flavour of Burley 2020. Dimensions
+// are taken four at a time from a shuffled and scrambled 4D Sobol sequence,
+// each group of four with its own seed so there is no limit on the number
+// of dimensions.
+pub struct SobolSampler {
+    state: SampleState,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler {
+            state: SampleState {
+                seed,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn value(&self, dimension: u32) -> f32 {
+        let group_seed = hash(&[self.state.pixel_hash, (dimension / 4) as u64]) as u32;
+        let index = nested_uniform_scramble(self.state.index, group_seed);
+        let component = (dimension % 4) as usize;
+        let scramble_seed = mix_bits(group_seed as u64 ^ component as u64) as u32;
+        to_unit_float(nested_uniform_scramble(
+            sobol(index, component),
+            scramble_seed,
+        ))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.state.start(x, y, index);
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.state.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        self.value(dimension)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.next_pair();
+        (self.value(dimension), self.value(dimension + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sampler::*;
+
+    // Every sampler should put exactly one of n samples in each of n equal
+    // intervals of the first dimension, except independent which is only
+    // checked for range.
+    fn strata_hit(sampler: &mut dyn Sampler, count: u32) -> Vec<u32> {
+        let mut hits = vec![0; count as usize];
+        for index in 0..count {
+            sampler.start_pixel_sample(3, 7, index);
+            let (u, v) = sampler.get_2d();
+            assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v));
+            hits[(u * count as f32) as usize] += 1;
+        }
+        hits
+    }
+
+    #[test]
+    fn test_samplers_are_stratified() {
+        for kind in [
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ] {
+            let count = if kind == SamplerKind::Halton { 8 } else { 16 };
+            let mut sampler = kind.create(16, 1);
+            let hits = strata_hit(sampler.as_mut(), count);
+            if kind == SamplerKind::Stratified {
+                // One per column of the 4x4 grid of strata
+                let columns: Vec<u32> = hits.chunks(4).map(|c| c.iter().sum()).collect();
+                assert_eq!(columns, vec![4, 4, 4, 4], "{kind:?}");
+            } else {
+                assert!(hits.iter().all(|&h| h == 1), "{kind:?} {hits:?}");
+            }
+        }
+        strata_hit(SamplerKind::Independent.create(16, 1).as_mut(), 16);
+    }
+
+    #[test]
+    fn test_samplers_are_deterministic() {
+        for kind in [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ] {
+            let mut a = kind.create(16, 42);
+            let mut b = kind.create(16, 42);
+            a.start_pixel_sample(5, 9, 3);
+            b.start_pixel_sample(5, 9, 3);
+            b.set_dimension(5);
+            let _ = a.get_2d();
+            let _ = a.get_2d();
+            let _ = a.get_1d();
+            assert_eq!(a.get_1d(), b.get_1d(), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn test_permutation_element_is_a_permutation() {
+        let mut seen: Vec<u32> = (0..13).map(|i| permutation_element(i, 13, 1234)).collect();
+        seen.sort();
+        assert_eq!(seen, (0..13).collect::<Vec<u32>>());
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::OnceLock;
+
+use crate::color::Color;
+use crate::vector::Vec3;
+
+// Range of visible wavelengths we sample, in nanometers
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_MAX: f32 = 780.0;
+
+// Wavelength used when a dispersive material is rendered in RGB, the
+// sodium D line that catalog IORs are usually quoted at.
+pub const LAMBDA_D: f32 = 587.6;
+
+// Uniformly pick a wavelength in the visible range from `u` in [0, 1)
+pub fn sample_wavelength(u: f32) -> f32 {
+    LAMBDA_MIN + u * (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+pub fn wavelength_pdf() -> f32 {
+    1.0 / (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+// Piecewise gaussian used by the matching function fit below
+fn g(x: f32, mu: f32, sigma_low: f32, sigma_high: f32) -> f32 {
+    let t = (x - mu) / if x < mu { sigma_low } else { sigma_high };
+    (-0.5 * t * t).exp()
+}
+
+// Analytic fit of the CIE 1931 2 degree color matching functions
+// https://jcgt.org/published/0002/02/01/paper.pdf
+pub fn cie_xyz(wavelength: f32) -> Vec3 {
+    let l = wavelength;
+    Vec3 {
+        x: 1.056 * g(l, 599.8, 37.9, 31.0) + 0.362 * g(l, 442.0, 16.0, 26.7)
+            - 0.065 * g(l, 501.1, 20.4, 26.2),
+        y: 0.821 * g(l, 568.8, 46.9, 40.5) + 0.286 * g(l, 530.9, 16.3, 31.1),
+        z: 1.217 * g(l, 437.0, 11.8, 36.0) + 0.681 * g(l, 459.0, 26.0, 13.8),
+    }
+}
+
+// CIE XYZ to linear sRGB primaries with a D65 white point
+pub fn xyz_to_linear_srgb(xyz: &Vec3) -> Color {
+    Color {
+        x: 3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        y: -0.969266 * xyz.x + 1.8760108 * xyz.y + 0.041556 * xyz.z,
+        z: 0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+    }
+}
+
+// RGB of a constant spectrum of one, used to scale so that spectral white
+// comes back out as RGB white.
+fn white_point() -> &'static Color {
+    static WHITE: OnceLock<Color> = OnceLock::new();
+    WHITE.get_or_init(|| {
+        let mut xyz = Vec3::zero();
+        let mut wavelength = LAMBDA_MIN;
+        while wavelength < LAMBDA_MAX {
+            xyz += cie_xyz(wavelength + 0.5);
+            wavelength += 1.0;
+        }
+        xyz_to_linear_srgb(&xyz)
+    })
+}
+
+// Turn a single wavelength sample of radiance into its RGB contribution,
+// dividing by the probability of having picked that wavelength.
+pub fn spectral_to_rgb(radiance: f32, wavelength: f32) -> Color {
+    let mut rgb = xyz_to_linear_srgb(&cie_xyz(wavelength)) * (radiance / wavelength_pdf());
+    rgb /= *white_point();
+    rgb
+}
+
+// Smits' basis spectra for RGB to spectrum conversion, 10 bins covering
+// 380 to 720 nm.
+// https://www.cs.utah.edu/~bes/papers/color/paper.pdf
+const SMITS_WHITE: [f32; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f32; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f32; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f32; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f32; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f32; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f32; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+// Value at `wavelength` of a smooth spectrum with (roughly) the given RGB
+// color, so albedos authored in RGB can take part in spectral rendering.
+pub fn rgb_to_spectrum(rgb: &Color, wavelength: f32) -> f32 {
+    let bin = (((wavelength - 380.0) / 34.0).floor().max(0.0) as usize).min(9);
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+
+    if r <= g && r <= b {
+        let base = r * SMITS_WHITE[bin];
+        if g <= b {
+            base + (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            base + (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        let base = g * SMITS_WHITE[bin];
+        if r <= b {
+            base + (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            base + (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        let base = b * SMITS_WHITE[bin];
+        if r <= g {
+            base + (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            base + (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::spectrum::*;
+
+    // Average many wavelength samples, stratified so the test is stable
+    fn integrate(rgb: &Color) -> Color {
+        let count = 4000;
+        let mut sum = Color::zero();
+        for i in 0..count {
+            let wavelength = sample_wavelength((i as f32 + 0.5) / count as f32);
+            sum += spectral_to_rgb(rgb_to_spectrum(rgb, wavelength), wavelength);
+        }
+        sum / count as f32
+    }
+
+    #[test]
+    fn test_white_round_trip() {
+        let white = integrate(&Color {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        });
+        assert!((white.x - 1.0).abs() < 0.01);
+        assert!((white.y - 1.0).abs() < 0.01);
+        assert!((white.z - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_color_round_trip() {
+        // Smits' basis is not exact, but saturated colors should come back
+        // recognisably
+        let rgb = Color {
+            x: 0.8,
+            y: 0.3,
+            z: 0.1,
+        };
+        let result = integrate(&rgb);
+        assert!((result.x - rgb.x).abs() < 0.1);
+        assert!((result.y - rgb.y).abs() < 0.1);
+        assert!((result.z - rgb.z).abs() < 0.1);
+    }
+}
@@ -1,13 +1,59 @@
 use crate::hittable::{HitRecord, Hittable};
+use crate::hittable_list::HittableList;
+use crate::material::Material;
 use crate::ray::Ray;
 use crate::vector::*;
-use crate::material::Material;
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
-    pub material: Rc<dyn Material>,
+    pub material: Arc<dyn Material>,
+    // Flip the normals so the outside of the sphere is its interior, used
+    // for the cavity of hollow objects.
+    pub inside_out: bool,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32, material: Arc<dyn Material>) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material,
+            inside_out: false,
+        }
+    }
+}
+
+// A shell of `thickness` whose outer surface has the given radius, e.g. a
+// glass bubble, made of a sphere and an inside out sphere for the cavity.
+pub fn hollow_sphere(
+    center: Vec3,
+    radius: f32,
+    thickness: f32,
+    material: Arc<dyn Material>,
+) -> HittableList {
+    HittableList {
+        objects: vec![
+            Box::new(Sphere::new(center, radius, Arc::clone(&material))),
+            Box::new(Sphere {
+                inside_out: true,
+                ..Sphere::new(center, radius - thickness, material)
+            }),
+        ],
+    }
+}
+
+// Longitude and latitude of a point on the unit sphere mapped to [0, 1],
+// with v going from the bottom pole to the top.
+// https://raytracing.github.io/books/RayTracingTheNextWeek.html#imagetexturemapping
+fn sphere_uv(p: &Vec3) -> (f32, f32) {
+    let theta = (-p.y).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z).atan2(p.x) + std::f32::consts::PI;
+    (
+        phi / (2.0 * std::f32::consts::PI),
+        theta / std::f32::consts::PI,
+    )
 }
 
 impl Hittable for Sphere {
@@ -32,15 +78,73 @@ impl Hittable for Sphere {
         }
 
         let point = ray.at(root);
+        let mut outward_normal = (point - self.center) / self.radius;
+        if self.inside_out {
+            outward_normal = -outward_normal;
+        }
+        let (u, v) = sphere_uv(&outward_normal);
         let mut rec = HitRecord {
             point,
             normal: point - self.center,
             t: root,
+            u,
+            v,
             front_face: true,
-            material: Rc::clone(&self.material),
+            exterior_ior: 1.0,
+            object_id: 0,
+            material: Arc::clone(&self.material),
         };
-        let outward_normal = (point - self.center) / self.radius;
         rec.set_face_normal(ray, &outward_normal);
         Some(rec)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::material::Lambertian;
+    use crate::sphere::*;
+
+    #[test]
+    fn test_inside_out_sphere() {
+        let material = Arc::new(Lambertian {
+            albedo: Vec3::zero(),
+        });
+        let center = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: -2.0,
+        };
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            wavelength: None,
+            time: 0.0,
+        };
+
+        let hit = Sphere::new(center, 0.5, material.clone())
+            .hit(&ray, 0.0, f32::INFINITY)
+            .unwrap();
+        assert!(hit.front_face);
+
+        // Same surface, but we are now looking at it from its interior,
+        // the normal still faces the ray
+        let sphere = Sphere {
+            inside_out: true,
+            ..Sphere::new(center, 0.5, material)
+        };
+        let hit = sphere.hit(&ray, 0.0, f32::INFINITY).unwrap();
+        assert!(!hit.front_face);
+        assert_eq!(
+            hit.normal,
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0
+            }
+        );
+    }
+}
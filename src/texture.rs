@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use crate::color::Color;
+use crate::hittable::HitRecord;
+use crate::vector::Vec3;
+
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f32, v: f32, point: &Vec3) -> Color;
+}
+
+pub struct SolidColor {
+    pub color: Color,
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f32, _v: f32, _point: &Vec3) -> Color {
+        self.color
+    }
+}
+
+// Alternating 3D checker pattern, `scale` is the size of one cell in world
+// units.
+pub struct Checker {
+    pub scale: f32,
+    pub even: Color,
+    pub odd: Color,
+}
+
+impl Texture for Checker {
+    fn value(&self, _u: f32, _v: f32, point: &Vec3) -> Color {
+        let inverse_scale = 1.0 / self.scale;
+        let sum = (inverse_scale * point.x).floor() as i32
+            + (inverse_scale * point.y).floor() as i32
+            + (inverse_scale * point.z).floor() as i32;
+        if sum % 2 == 0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+// A material input that is either a constant or driven by a texture.
+// Scalar inputs such as roughness read the first channel.
+#[derive(Clone)]
+pub enum Parameter {
+    Constant(Color),
+    Texture(Arc<dyn Texture>),
+}
+
+impl Parameter {
+    pub fn evaluate(&self, hit: &HitRecord) -> Color {
+        match self {
+            Parameter::Constant(color) => *color,
+            Parameter::Texture(texture) => texture.value(hit.u, hit.v, &hit.point),
+        }
+    }
+
+    pub fn scalar(&self, hit: &HitRecord) -> f32 {
+        self.evaluate(hit).x
+    }
+}
+
+impl From<f32> for Parameter {
+    fn from(value: f32) -> Self {
+        Parameter::Constant(Color {
+            x: value,
+            y: value,
+            z: value,
+        })
+    }
+}
+
+impl From<Color> for Parameter {
+    fn from(color: Color) -> Self {
+        Parameter::Constant(color)
+    }
+}
+
+impl From<Arc<dyn Texture>> for Parameter {
+    fn from(texture: Arc<dyn Texture>) -> Self {
+        Parameter::Texture(texture)
+    }
+}
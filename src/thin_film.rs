@@ -0,0 +1,190 @@
+use std::f32::consts::PI;
+use std::ops::{Add, Div, Mul, Sub};
+
+use crate::color::Color;
+
+// Wavelengths in nanometers standing in for the red, green and blue channels
+// when evaluating interference per channel.
+pub const RGB_WAVELENGTHS: [f32; 3] = [630.0, 532.0, 465.0];
+
+// Just enough complex arithmetic for the Fresnel amplitudes of absorbing
+// media.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Complex {
+        Complex { re, im }
+    }
+
+    pub fn real(re: f32) -> Complex {
+        Complex { re, im: 0.0 }
+    }
+
+    pub fn norm_squared(&self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    // Principal square root
+    pub fn sqrt(&self) -> Complex {
+        let norm = self.norm_squared().sqrt();
+        let re = (0.5 * (norm + self.re)).max(0.0).sqrt();
+        let im = (0.5 * (norm - self.re)).max(0.0).sqrt();
+        Complex {
+            re,
+            im: if self.im < 0.0 { -im } else { im },
+        }
+    }
+
+    // e^(i phase)
+    pub fn from_phase(phase: f32) -> Complex {
+        Complex {
+            re: phase.cos(),
+            im: phase.sin(),
+        }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+
+    fn div(self, other: Complex) -> Complex {
+        let denominator = other.norm_squared();
+        Complex::new(
+            (self.re * other.re + self.im * other.im) / denominator,
+            (self.im * other.re - self.re * other.im) / denominator,
+        )
+    }
+}
+
+// Cosine of the angle inside a medium given Snell's invariant n sin(theta)
+fn cos_inside(invariant: f32, ior: Complex) -> Complex {
+    let sin = Complex::real(invariant) / ior;
+    (Complex::real(1.0) - sin * sin).sqrt()
+}
+
+// Reflectance of a single film of `film_ior` and `thickness` nanometers,
+// sitting between an incident medium and a possibly absorbing substrate.
+// Sums the infinite series of internal reflections (Airy formula) for s and
+// p polarization and averages them.
+// https://en.wikipedia.org/wiki/Thin-film_interference
+pub fn thin_film_reflectance(
+    cos_theta_i: f32,
+    incident_ior: f32,
+    film_ior: f32,
+    thickness: f32,
+    substrate_ior: Complex,
+    wavelength: f32,
+) -> f32 {
+    let n1 = Complex::real(incident_ior);
+    let n2 = Complex::real(film_ior);
+    let n3 = substrate_ior;
+
+    let cos_theta_i = cos_theta_i.clamp(0.0, 1.0);
+    let c1 = Complex::real(cos_theta_i);
+    let invariant = incident_ior * (1.0 - cos_theta_i * cos_theta_i).sqrt();
+    let c2 = cos_inside(invariant, n2);
+    let c3 = cos_inside(invariant, n3);
+
+    let r_s = |ni: Complex, ci: Complex, nj: Complex, cj: Complex| {
+        (ni * ci - nj * cj) / (ni * ci + nj * cj)
+    };
+    let r_p = |ni: Complex, ci: Complex, nj: Complex, cj: Complex| {
+        (nj * ci - ni * cj) / (nj * ci + ni * cj)
+    };
+
+    // Phase difference picked up crossing the film and back
+    let phase = Complex::real(4.0 * PI * thickness / wavelength) * n2 * c2;
+    let shift = Complex::from_phase(phase.re) * Complex::real((-phase.im).exp());
+
+    let airy = |r12: Complex, r23: Complex| {
+        let r = (r12 + r23 * shift) / (Complex::real(1.0) + r12 * r23 * shift);
+        r.norm_squared()
+    };
+
+    let reflectance_s = airy(r_s(n1, c1, n2, c2), r_s(n2, c2, n3, c3));
+    let reflectance_p = airy(r_p(n1, c1, n2, c2), r_p(n2, c2, n3, c3));
+    (0.5 * (reflectance_s + reflectance_p)).clamp(0.0, 1.0)
+}
+
+// Per channel thin film reflectance at `RGB_WAVELENGTHS`, the substrate IOR
+// may differ per channel for conductors.
+pub fn thin_film_reflectance_rgb(
+    cos_theta_i: f32,
+    incident_ior: f32,
+    film_ior: f32,
+    thickness: f32,
+    substrate_ior: [Complex; 3],
+) -> Color {
+    let channel = |i: usize| {
+        thin_film_reflectance(
+            cos_theta_i,
+            incident_ior,
+            film_ior,
+            thickness,
+            substrate_ior[i],
+            RGB_WAVELENGTHS[i],
+        )
+    };
+    Color {
+        x: channel(0),
+        y: channel(1),
+        z: channel(2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::microfacet::fresnel_dielectric;
+    use crate::thin_film::*;
+
+    #[test]
+    fn test_vanishing_film_matches_fresnel() {
+        // Without thickness the film is invisible and we are left with the
+        // bare interface
+        for cos in [1.0, 0.7, 0.3] {
+            let r = thin_film_reflectance(cos, 1.0, 1.33, 0.0, Complex::real(1.5), 550.0);
+            assert!((r - fresnel_dielectric(cos, 1.5)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_quarter_wave_antireflection() {
+        // A quarter wave coating with IOR sqrt(1.5) cancels reflection off
+        // glass at its design wavelength
+        let film_ior = 1.5_f32.sqrt();
+        let thickness = 550.0 / (4.0 * film_ior);
+        let r = thin_film_reflectance(1.0, 1.0, film_ior, thickness, Complex::real(1.5), 550.0);
+        assert!(r < 1e-4);
+    }
+}
@@ -21,12 +21,14 @@ impl Vec3 {
     }
 
     pub fn near_zero(&self) -> bool {
-        return self.x.abs() < f32::MIN_POSITIVE && self.y.abs() < f32::MIN_POSITIVE && self.z.abs() < f32::MIN_POSITIVE;
+        self.x.abs() < f32::MIN_POSITIVE
+            && self.y.abs() < f32::MIN_POSITIVE
+            && self.z.abs() < f32::MIN_POSITIVE
     }
 }
 
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
-    *v - 2.0 * dot(v,n) * *n
+    *v - 2.0 * dot(v, n) * *n
 }
 
 // https://raytracing.github.io/books/RayTracingInOneWeekend.html#dielectrics/snell'slaw
@@ -34,7 +36,7 @@ pub fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: f32) -> Vec3 {
     let cos_theta = dot(&-*uv, n).min(1.0);
     let r_out_perp: Vec3 = etai_over_etat * (*uv + cos_theta * *n);
     let r_out_parallel: Vec3 = -((1.0 - r_out_perp.length_squared()).abs().sqrt()) * *n;
-    return r_out_perp + r_out_parallel;
+    r_out_perp + r_out_parallel
 }
 
 #[inline]
@@ -60,6 +62,39 @@ pub fn random_unit_vector() -> Vec3 {
     unit_vector(&random_in_unit_sphere())
 }
 
+// Uniformly distributed direction from a pair of numbers in [0, 1)
+pub fn sample_unit_vector(u: (f32, f32)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u.1;
+    Vec3 {
+        x: r * phi.cos(),
+        y: r * phi.sin(),
+        z,
+    }
+}
+
+// Uniformly distributed point on the unit disk in the z = 0 plane, with
+// Shirley's concentric mapping which keeps strata intact
+pub fn sample_unit_disk(u: (f32, f32)) -> Vec3 {
+    let a = 2.0 * u.0 - 1.0;
+    let b = 2.0 * u.1 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec3::zero();
+    }
+    let quarter_pi = std::f32::consts::FRAC_PI_4;
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, quarter_pi * (b / a))
+    } else {
+        (b, 2.0 * quarter_pi - quarter_pi * (a / b))
+    };
+    Vec3 {
+        x: r * theta.cos(),
+        y: r * theta.sin(),
+        z: 0.0,
+    }
+}
+
 // Implement operator traits,
 impl Neg for Vec3 {
     type Output = Self; // TODO: figure out this standard,
@@ -271,6 +306,49 @@ pub fn unit_vector(v: &Vec3) -> Vec3 {
     *v / v.length()
 }
 
+// Orthonormal basis around `w`, used to move directions in and out of a
+// shading frame where the normal is +z.
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    // https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+    pub fn from_w(n: &Vec3) -> Onb {
+        let w = unit_vector(n);
+        let sign = 1.0_f32.copysign(w.z);
+        let a = -1.0 / (sign + w.z);
+        let b = w.x * w.y * a;
+        Onb {
+            u: Vec3 {
+                x: 1.0 + sign * w.x * w.x * a,
+                y: sign * b,
+                z: -sign * w.x,
+            },
+            v: Vec3 {
+                x: b,
+                y: sign + w.y * w.y * a,
+                z: -w.y,
+            },
+            w,
+        }
+    }
+
+    pub fn to_local(&self, a: &Vec3) -> Vec3 {
+        Vec3 {
+            x: dot(a, &self.u),
+            y: dot(a, &self.v),
+            z: dot(a, &self.w),
+        }
+    }
+
+    pub fn to_world(&self, a: &Vec3) -> Vec3 {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::vector::*;